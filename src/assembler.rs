@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+
+use crate::hack::{KBD, ROM_SIZE, SCREEN};
+
+// The first address given to a variable, right after R0-R15
+const FIRST_VARIABLE: u16 = 16;
+
+lazy_static! {
+    static ref PREDEFINED: HashMap<String, u16> = {
+        let mut symbols: HashMap<String, u16> = [
+            ("SP", 0),
+            ("LCL", 1),
+            ("ARG", 2),
+            ("THIS", 3),
+            ("THAT", 4),
+            ("SCREEN", SCREEN as u16),
+            ("KBD", KBD as u16),
+        ]
+        .into_iter()
+        .map(|(name, address)| (name.to_string(), address))
+        .collect();
+        for i in 0..16 {
+            symbols.insert(format!("R{i}"), i);
+        }
+        symbols
+    };
+}
+
+// The a bit and the six ALU control bits of every computation
+fn comp_bits(comp: &str) -> Option<u16> {
+    let bits = match comp {
+        "0" => 0b0101010,
+        "1" => 0b0111111,
+        "-1" => 0b0111010,
+        "D" => 0b0001100,
+        "A" => 0b0110000,
+        "M" => 0b1110000,
+        "!D" => 0b0001101,
+        "!A" => 0b0110001,
+        "!M" => 0b1110001,
+        "-D" => 0b0001111,
+        "-A" => 0b0110011,
+        "-M" => 0b1110011,
+        "D+1" | "1+D" => 0b0011111,
+        "A+1" | "1+A" => 0b0110111,
+        "M+1" | "1+M" => 0b1110111,
+        "D-1" => 0b0001110,
+        "A-1" => 0b0110010,
+        "M-1" => 0b1110010,
+        "D+A" | "A+D" => 0b0000010,
+        "D+M" | "M+D" => 0b1000010,
+        "D-A" => 0b0010011,
+        "D-M" => 0b1010011,
+        "A-D" => 0b0000111,
+        "M-D" => 0b1000111,
+        "D&A" | "A&D" => 0b0000000,
+        "D&M" | "M&D" => 0b1000000,
+        "D|A" | "A|D" => 0b0010101,
+        "D|M" | "M|D" => 0b1010101,
+        _ => return None,
+    };
+    Some(bits)
+}
+
+fn jump_bits(jump: &str) -> Option<u16> {
+    let bits = match jump {
+        "JGT" => 1,
+        "JEQ" => 2,
+        "JGE" => 3,
+        "JLT" => 4,
+        "JNE" => 5,
+        "JLE" => 6,
+        "JMP" => 7,
+        _ => return None,
+    };
+    Some(bits)
+}
+
+// Any combination of A, M and D, each at most once
+fn dest_bits(dest: &str) -> Option<u16> {
+    let mut bits = 0;
+    for register in dest.chars() {
+        let bit = match register {
+            'A' => 4,
+            'D' => 2,
+            'M' => 1,
+            _ => return None,
+        };
+        if bits & bit != 0 {
+            return None;
+        }
+        bits |= bit;
+    }
+    Some(bits)
+}
+
+fn is_symbol(name: &str) -> bool {
+    let valid = |c: char| c.is_ascii_alphanumeric() || "_.$:".contains(c);
+    name.chars().all(valid) && name.chars().next().is_some_and(|c| !c.is_ascii_digit())
+}
+
+// Each bad line, numbered from 1, and what is wrong with it
+pub type LineErrors = Vec<(usize, String)>;
+
+enum Instruction<'a> {
+    Address(&'a str),
+    Compute(&'a str),
+}
+
+// Turns Hack assembly into machine code, one word per instruction
+// Every malformed line is reported along with the line it's on, counting from 1
+pub fn assemble(source: &str) -> Result<Vec<u16>, LineErrors> {
+    let mut errors = vec![];
+    let mut symbols = PREDEFINED.clone();
+
+    // First pass finds where every label points
+    let mut instructions = vec![];
+    for (i, line) in source.lines().enumerate() {
+        let line = line.split("//").next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(label) = line.strip_prefix('(') {
+            let Some(label) = label.strip_suffix(')').filter(|l| is_symbol(l)) else {
+                errors.push((i + 1, format!("`{line}` is not a valid label")));
+                continue;
+            };
+            if symbols.contains_key(label) {
+                errors.push((i + 1, format!("`{label}` is already defined")));
+            }
+            symbols.insert(label.to_string(), instructions.len() as u16);
+        } else if let Some(address) = line.strip_prefix('@') {
+            instructions.push((i + 1, Instruction::Address(address)));
+        } else {
+            instructions.push((i + 1, Instruction::Compute(line)));
+        }
+        if instructions.len() > ROM_SIZE {
+            errors.push((
+                i + 1,
+                format!("the program doesn't fit in {ROM_SIZE} words of ROM"),
+            ));
+            return Err(errors);
+        }
+    }
+
+    // Second pass, anything that isn't a label by now is a variable
+    let mut next_variable = FIRST_VARIABLE;
+    let mut words = vec![];
+    for (line, instruction) in instructions {
+        let word = match instruction {
+            Instruction::Address(value) => match value.parse::<usize>() {
+                Ok(n) if n < ROM_SIZE => Ok(n as u16),
+                Ok(_) => Err(format!(
+                    "`{value}` is too large, the limit is {}",
+                    ROM_SIZE - 1
+                )),
+                Err(_) if is_symbol(value) => {
+                    Ok(*symbols.entry(value.to_string()).or_insert_with(|| {
+                        next_variable += 1;
+                        next_variable - 1
+                    }))
+                }
+                Err(_) => Err(format!("`@{value}` is not a valid address or symbol")),
+            },
+            Instruction::Compute(code) => compute(code),
+        };
+        match word {
+            Ok(word) => words.push(word),
+            Err(message) => errors.push((line, message)),
+        }
+    }
+    if errors.is_empty() {
+        Ok(words)
+    } else {
+        errors.sort_by_key(|(line, _)| *line);
+        Err(errors)
+    }
+}
+
+// dest=comp;jump, where dest and jump are optional
+fn compute(code: &str) -> Result<u16, String> {
+    let code: String = code.split_whitespace().collect();
+    let (dest, rest) = code.split_once('=').unwrap_or(("", &code));
+    let (comp, jump) = rest.split_once(';').unwrap_or((rest, ""));
+    let dest = dest_bits(dest).ok_or_else(|| format!("`{dest}` is not a valid destination"))?;
+    let comp = comp_bits(comp).ok_or_else(|| format!("`{comp}` is not a valid computation"))?;
+    let jump = match jump {
+        "" => 0,
+        _ => jump_bits(jump).ok_or_else(|| format!("`{jump}` is not a valid jump"))?,
+    };
+    Ok(0b111 << 13 | comp << 6 | dest << 3 | jump)
+}
+
+// The .hack text format, each word as 16 binary digits on its own line
+pub fn to_hack(words: &[u16]) -> String {
+    words.iter().map(|word| format!("{word:016b}\n")).collect()
+}
+
+// Reads back the .hack text format, blank lines are skipped
+pub fn from_hack(source: &str) -> Result<Vec<u16>, LineErrors> {
+    let mut errors = vec![];
+    let mut words = vec![];
+    for (i, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match u16::from_str_radix(line, 2) {
+            Ok(word) if line.len() == 16 => words.push(word),
+            _ => errors.push((i + 1, format!("`{line}` is not 16 binary digits"))),
+        }
+    }
+    if words.len() > ROM_SIZE {
+        errors.push((
+            source.lines().count(),
+            format!("the program doesn't fit in {ROM_SIZE} words of ROM"),
+        ));
+    }
+    if errors.is_empty() {
+        Ok(words)
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble() {
+        let source =
+            "// Adds R0 and R1\n@R0\nD=M\n@R1\nD=D+M\n@sum\nM=D\n(LOOP)\n  @LOOP // forever\n\
+                      0;JMP\n@i\nAM=M+1\n@sum\nDM=D|A;JNE\n@SCREEN\n@32767";
+        assert_eq!(
+            to_hack(&assemble(source).unwrap()),
+            "0000000000000000\n1111110000010000\n0000000000000001\n1111000010010000\n\
+             0000000000010000\n1110001100001000\n0000000000000110\n1110101010000111\n\
+             0000000000010001\n1111110111101000\n0000000000010000\n1110010101011101\n\
+             0100000000000000\n0111111111111111\n"
+        );
+
+        let errors =
+            assemble("@32768\nD=D*A\nAA=D\nD;JUMP\n(LOOP)\n(LOOP)\n@1x\n(1x)").unwrap_err();
+        assert_eq!(
+            errors,
+            [
+                (1, String::from("`32768` is too large, the limit is 32767")),
+                (2, String::from("`D*A` is not a valid computation")),
+                (3, String::from("`AA` is not a valid destination")),
+                (4, String::from("`JUMP` is not a valid jump")),
+                (6, String::from("`LOOP` is already defined")),
+                (7, String::from("`@1x` is not a valid address or symbol")),
+                (8, String::from("`(1x)` is not a valid label")),
+            ]
+        );
+    }
+}
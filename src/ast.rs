@@ -0,0 +1,274 @@
+use std::fmt::Display;
+
+use crate::{
+    symbol_table::Kind,
+    tokenizer::Span,
+    tokens::{Keyword, Token},
+};
+
+// Syntax tree for a single Jack class, produced by the CompilationEngine
+// Every node keeps the span of the source it was parsed from
+
+#[derive(Debug, Clone)]
+pub struct Class {
+    pub name: Ident,
+    pub vars: Vec<ClassVarDec>,
+    pub subroutines: Vec<Subroutine>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ident {
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Type {
+    Int,
+    Char,
+    Boolean,
+    Class(String),
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Char => write!(f, "char"),
+            Type::Boolean => write!(f, "boolean"),
+            Type::Class(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+impl Type {
+    // Types are written as a keyword for the primitives or the name of a class
+    pub fn from_token(token: &Token) -> Option<Self> {
+        match token {
+            Token::Keyword(Keyword::Int) => Some(Type::Int),
+            Token::Keyword(Keyword::Char) => Some(Type::Char),
+            Token::Keyword(Keyword::Boolean) => Some(Type::Boolean),
+            Token::Identifier(name) => Some(Type::Class(name.clone())),
+            _ => None,
+        }
+    }
+
+    // The inverse of Display, for types stored by name in the SymbolTable
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "int" => Type::Int,
+            "char" => Type::Char,
+            "boolean" => Type::Boolean,
+            _ => Type::Class(String::from(name)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClassVarDec {
+    // Only ever Kind::Static or Kind::Field
+    pub kind: Kind,
+    pub var_type: Type,
+    pub names: Vec<Ident>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubroutineKind {
+    Constructor,
+    Function,
+    Method,
+}
+
+impl Display for SubroutineKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SubroutineKind::Constructor => write!(f, "constructor"),
+            SubroutineKind::Function => write!(f, "function"),
+            SubroutineKind::Method => write!(f, "method"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Subroutine {
+    pub kind: SubroutineKind,
+    // None for void
+    pub return_type: Option<Type>,
+    pub name: Ident,
+    pub params: Vec<Parameter>,
+    pub vars: Vec<VarDec>,
+    pub statements: Vec<Statement>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub struct Parameter {
+    pub var_type: Type,
+    pub name: Ident,
+}
+
+#[derive(Debug, Clone)]
+pub struct VarDec {
+    pub var_type: Type,
+    pub names: Vec<Ident>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum Statement {
+    Let {
+        target: Ident,
+        index: Option<Expr>,
+        value: Expr,
+        span: Span,
+    },
+    If {
+        condition: Expr,
+        then: Vec<Statement>,
+        otherwise: Option<Vec<Statement>>,
+        span: Span,
+    },
+    While {
+        condition: Expr,
+        body: Vec<Statement>,
+        span: Span,
+    },
+    Do {
+        call: SubroutineCall,
+        span: Span,
+    },
+    Return {
+        value: Option<Expr>,
+        span: Span,
+    },
+}
+
+impl Statement {
+    pub fn span(&self) -> Span {
+        match self {
+            Statement::Let { span, .. }
+            | Statement::If { span, .. }
+            | Statement::While { span, .. }
+            | Statement::Do { span, .. }
+            | Statement::Return { span, .. } => *span,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Term(Term),
+    Binary {
+        op: BinaryOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+        span: Span,
+    },
+}
+
+impl Expr {
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::Term(t) => t.span(),
+            Expr::Binary { span, .. } => *span,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Term {
+    // Integer, string and keyword constants, kept as the token they were parsed from
+    Constant(Token, Span),
+    Var(Ident),
+    Index(Ident, Box<Expr>, Span),
+    Call(SubroutineCall),
+    Paren(Box<Expr>, Span),
+    Unary(UnaryOp, Box<Term>, Span),
+}
+
+impl Term {
+    pub fn span(&self) -> Span {
+        match self {
+            Term::Constant(_, span)
+            | Term::Index(_, _, span)
+            | Term::Paren(_, span)
+            | Term::Unary(_, _, span) => *span,
+            Term::Var(ident) => ident.span,
+            Term::Call(call) => call.span,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SubroutineCall {
+    // The class or variable before the '.', if there is one
+    pub receiver: Option<Ident>,
+    pub name: Ident,
+    pub args: Vec<Expr>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    And,
+    Or,
+    Lt,
+    Gt,
+    Eq,
+}
+
+impl BinaryOp {
+    pub fn from_symbol(c: char) -> Option<Self> {
+        match c {
+            '+' => Some(BinaryOp::Add),
+            '-' => Some(BinaryOp::Sub),
+            '*' => Some(BinaryOp::Mul),
+            '/' => Some(BinaryOp::Div),
+            '%' => Some(BinaryOp::Mod),
+            '&' => Some(BinaryOp::And),
+            '|' => Some(BinaryOp::Or),
+            '<' => Some(BinaryOp::Lt),
+            '>' => Some(BinaryOp::Gt),
+            '=' => Some(BinaryOp::Eq),
+            _ => None,
+        }
+    }
+
+    pub fn symbol(self) -> char {
+        match self {
+            BinaryOp::Add => '+',
+            BinaryOp::Sub => '-',
+            BinaryOp::Mul => '*',
+            BinaryOp::Div => '/',
+            BinaryOp::Mod => '%',
+            BinaryOp::And => '&',
+            BinaryOp::Or => '|',
+            BinaryOp::Lt => '<',
+            BinaryOp::Gt => '>',
+            BinaryOp::Eq => '=',
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+impl UnaryOp {
+    pub fn from_symbol(c: char) -> Option<Self> {
+        match c {
+            '-' => Some(UnaryOp::Neg),
+            '~' => Some(UnaryOp::Not),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,619 @@
+use std::fmt::Display;
+
+use crate::{
+    ast::*,
+    compilation_engine::CompilationError,
+    diagnostics::{Diagnostic, Level},
+    program_index::{ProgramIndex, Signature},
+    symbol_table::*,
+    tokenizer::Span,
+    tokens::{Keyword, Token},
+    vm_writer::{Comparison::*, MemSegment as Mem, VmCommand},
+};
+
+// The VM code for a single class, along with any warnings found while compiling it
+#[derive(Debug, Clone)]
+pub struct VmProgram {
+    pub class_name: String,
+    pub commands: Vec<VmCommand>,
+    pub warnings: Vec<Diagnostic>,
+}
+
+// The contents of the class's .vm file
+impl Display for VmProgram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for command in &self.commands {
+            writeln!(f, "{command}")?;
+        }
+        Ok(())
+    }
+}
+
+// How the labels for if and while statements are named
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LabelStyle {
+    // Main.main$IF_TRUE0, unique across the whole program
+    Scoped,
+    // IF_TRUE0, exactly what the course's JackCompiler writes for easy diffing
+    Reference,
+}
+
+impl LabelStyle {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "scoped" => Some(LabelStyle::Scoped),
+            "reference" => Some(LabelStyle::Reference),
+            _ => None,
+        }
+    }
+}
+
+// Walks the syntax tree of a single class and collects its VM code
+pub struct CodeGenerator<'a> {
+    // Every class in the program, for checking calls
+    index: &'a ProgramIndex,
+    commands: Vec<VmCommand>,
+    class_name: String,
+    labels: LabelStyle,
+    subroutine_name: String,
+    // Functions have no `this`, so no fields or implicit method calls either
+    subroutine_kind: SubroutineKind,
+    symbol_table: SymbolTable,
+    diagnostics: Vec<Diagnostic>,
+    if_counter: u16,
+    while_counter: u16,
+}
+
+impl<'a> CodeGenerator<'a> {
+    pub fn new(index: &'a ProgramIndex) -> Self {
+        CodeGenerator {
+            index,
+            commands: vec![],
+            class_name: String::new(),
+            labels: LabelStyle::Scoped,
+            subroutine_name: String::new(),
+            subroutine_kind: SubroutineKind::Function,
+            symbol_table: SymbolTable::default(),
+            diagnostics: vec![],
+            if_counter: 0,
+            while_counter: 0,
+        }
+    }
+
+    pub fn with_labels(mut self, labels: LabelStyle) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    fn write(&mut self, command: VmCommand) {
+        self.commands.push(command);
+    }
+
+    fn throw_error_at(&mut self, error: CompilationError, span: Span) {
+        self.diagnostics.push(Diagnostic {
+            level: Level::Error,
+            error,
+            span,
+            context: None,
+        });
+    }
+
+    // Returns the class's code and any problems found along the way, like undeclared variables
+    pub fn generate(mut self, class: &Class) -> (Vec<VmCommand>, Vec<Diagnostic>) {
+        self.class_name = class.name.name.clone();
+        let (symbol_table, duplicates) = SymbolTable::for_class(class);
+        self.symbol_table = symbol_table;
+        for (e, span) in duplicates {
+            self.throw_error_at(e, span);
+        }
+        for subroutine in &class.subroutines {
+            self.compile_subroutine(subroutine);
+        }
+        (self.commands, self.diagnostics)
+    }
+
+    fn compile_subroutine(&mut self, subroutine: &Subroutine) {
+        self.subroutine_name = subroutine.name.name.clone();
+        self.subroutine_kind = subroutine.kind;
+        self.if_counter = 0;
+        self.while_counter = 0;
+
+        // Jack methods include "this" as their first unspoken argument
+        for (e, span) in self
+            .symbol_table
+            .enter_subroutine(&self.class_name, subroutine)
+        {
+            self.throw_error_at(e, span);
+        }
+
+        // Declare function now that the symbol table is complete
+        self.write(VmCommand::Function(
+            format!("{}.{}", self.class_name, subroutine.name.name),
+            self.symbol_table.var_count(Kind::Var),
+        ));
+
+        match subroutine.kind {
+            SubroutineKind::Constructor => {
+                // Constructors require allocating enough memory for all fields
+                self.write(VmCommand::Push(
+                    Mem::Constant,
+                    self.symbol_table.var_count(Kind::Field),
+                ));
+                self.write(VmCommand::Call(String::from("Memory.alloc"), 1));
+                self.write(VmCommand::Pop(Mem::Pointer, 0));
+            }
+            SubroutineKind::Method => {
+                // Methods require a pointer to the current object
+                self.write(VmCommand::Push(Mem::Argument, 0));
+                self.write(VmCommand::Pop(Mem::Pointer, 0));
+            }
+            SubroutineKind::Function => {}
+        }
+        self.compile_statements(&subroutine.statements);
+    }
+
+    fn compile_statements(&mut self, statements: &[Statement]) {
+        for statement in statements {
+            match statement {
+                Statement::Let {
+                    target,
+                    index,
+                    value,
+                    ..
+                } => self.compile_let(target, index.as_ref(), value),
+                Statement::If {
+                    condition,
+                    then,
+                    otherwise,
+                    ..
+                } => self.compile_if(condition, then, otherwise.as_deref()),
+                Statement::While {
+                    condition, body, ..
+                } => self.compile_while(condition, body),
+                Statement::Do { call, .. } => {
+                    self.compile_subroutine_call(call);
+                    // All "do" statements in Jack are "void" function calls
+                    // which require discarding the return value that the VM implementation requires
+                    self.write(VmCommand::Pop(Mem::Temp, 0));
+                }
+                Statement::Return { value, .. } => {
+                    if let Some(value) = value {
+                        self.compile_expression(value);
+                    } else {
+                        // The VM requires that a value is returned even if the type is void
+                        self.write(VmCommand::Push(Mem::Constant, 0));
+                    }
+                    self.write(VmCommand::Return);
+                }
+            }
+        }
+    }
+
+    // Finds where a variable lives, reporting it if it was never declared
+    fn lookup(&mut self, name: &Ident) -> (Mem, i16) {
+        if let Some(entry) = self.symbol_table.get(&name.name) {
+            let (kind, id) = (entry.get_kind(), entry.get_id());
+            self.check_field_access(kind, name);
+            (kind.to_mem_seg(), id)
+        } else {
+            self.throw_error_at(
+                CompilationError::UndeclaredIdentifier(name.name.clone()),
+                name.span,
+            );
+            (Mem::Constant, 0)
+        }
+    }
+
+    fn check_field_access(&mut self, kind: Kind, name: &Ident) {
+        if kind == Kind::Field && self.subroutine_kind == SubroutineKind::Function {
+            self.throw_error_at(
+                CompilationError::FieldInFunction(name.name.clone()),
+                name.span,
+            );
+        }
+    }
+
+    fn compile_let(&mut self, target: &Ident, index: Option<&Expr>, value: &Expr) {
+        let (mut seg, mut id) = self.lookup(target);
+        if let Some(index) = index {
+            self.compile_expression(index);
+            self.write(VmCommand::Push(seg, id));
+            self.write(VmCommand::Add);
+            (seg, id) = (Mem::That, 0);
+        }
+        self.compile_expression(value);
+        if index.is_some() {
+            // The value is stashed while `that` is pointed at the array entry
+            // in case evaluating it moved the pointer
+            self.write(VmCommand::Pop(Mem::Temp, 0));
+            self.write(VmCommand::Pop(Mem::Pointer, 1));
+            self.write(VmCommand::Push(Mem::Temp, 0));
+        }
+        self.write(VmCommand::Pop(seg, id));
+    }
+
+    fn compile_while(&mut self, condition: &Expr, body: &[Statement]) {
+        let n = self.while_counter;
+        self.while_counter += 1;
+        let start_label = self.label("WHILE_EXP", n);
+        let end_label = self.label("WHILE_END", n);
+
+        // Place the starting label just prior to evaluating the condition
+        self.write(VmCommand::Label(start_label.clone()));
+        self.compile_expression(condition);
+
+        // Bypass loop if negated condition is true
+        self.write(VmCommand::Not);
+        self.write(VmCommand::IfGoto(end_label.clone()));
+
+        // Inside loop and jump to start
+        self.compile_statements(body);
+        self.write(VmCommand::Goto(start_label));
+
+        // Label at the end of loop
+        self.write(VmCommand::Label(end_label));
+    }
+
+    fn compile_if(
+        &mut self,
+        condition: &Expr,
+        then: &[Statement],
+        otherwise: Option<&[Statement]>,
+    ) {
+        let n = self.if_counter;
+        self.if_counter += 1;
+        let true_label = self.label("IF_TRUE", n);
+        let false_label = self.label("IF_FALSE", n);
+        let end_label = self.label("IF_END", n);
+
+        self.compile_expression(condition);
+        if self.labels == LabelStyle::Reference {
+            // Jumps over the else branch without negating the condition,
+            // and only needs an end label if there is an else branch
+            self.write(VmCommand::IfGoto(true_label.clone()));
+            self.write(VmCommand::Goto(false_label.clone()));
+            self.write(VmCommand::Label(true_label));
+            self.compile_statements(then);
+            if let Some(otherwise) = otherwise {
+                self.write(VmCommand::Goto(end_label.clone()));
+                self.write(VmCommand::Label(false_label));
+                self.compile_statements(otherwise);
+                self.write(VmCommand::Label(end_label));
+            } else {
+                self.write(VmCommand::Label(false_label));
+            }
+            return;
+        }
+
+        // Negate for simpler if-goto
+        self.write(VmCommand::Not);
+        self.write(VmCommand::IfGoto(false_label.clone()));
+        self.compile_statements(then);
+        self.write(VmCommand::Goto(end_label.clone()));
+        self.write(VmCommand::Label(false_label));
+        if let Some(otherwise) = otherwise {
+            self.compile_statements(otherwise);
+        }
+        self.write(VmCommand::Label(end_label));
+    }
+
+    // Returns the full name of the subroutine and its signature, if it could be found
+    fn compile_subroutine_call(
+        &mut self,
+        call: &SubroutineCall,
+    ) -> Option<(String, &'a Signature)> {
+        // Easy way to add an extra argument if we determine the subroutine is a method and requires 'this'
+        let mut method = false;
+        let class_name = match &call.receiver {
+            // If the name is in the table we get its class for the label and push it so the method can be called
+            // Otherwise, it's simply a class function on its own
+            Some(receiver) => match self.symbol_table.get(&receiver.name) {
+                Some(entry) => {
+                    let (kind, id) = (entry.get_kind(), entry.get_id());
+                    let class_name = entry.get_type().to_string();
+                    self.check_field_access(kind, receiver);
+                    self.write(VmCommand::Push(kind.to_mem_seg(), id));
+                    method = true;
+                    class_name
+                }
+                None => receiver.name.clone(),
+            },
+            // Any calls without a '.' will be called from within this class
+            // so we can simply use the class name
+            None => {
+                self.write(VmCommand::Push(Mem::Pointer, 0));
+                method = true;
+                self.class_name.clone()
+            }
+        };
+        let signature = self.check_call(call, &class_name, method);
+        let func_label = format!("{class_name}.{}", call.name.name);
+
+        for arg in &call.args {
+            self.compile_expression(arg);
+        }
+        self.write(VmCommand::Call(
+            func_label.clone(),
+            call.args.len() as i16 + method as i16,
+        ));
+        signature.map(|s| (func_label, s))
+    }
+
+    fn compile_term(&mut self, term: &Term) {
+        match term {
+            Term::Constant(token, span) => {
+                if *token == Token::Keyword(Keyword::This)
+                    && self.subroutine_kind == SubroutineKind::Function
+                {
+                    self.throw_error_at(CompilationError::ThisInFunction, *span);
+                }
+                self.write_constant(token)
+            }
+            Term::Var(name) => {
+                let (seg, id) = self.lookup(name);
+                self.write(VmCommand::Push(seg, id));
+            }
+            Term::Index(name, index, _) => {
+                self.compile_expression(index);
+                let (seg, id) = self.lookup(name);
+                self.write(VmCommand::Push(seg, id));
+                self.write(VmCommand::Add);
+                self.write(VmCommand::Pop(Mem::Pointer, 1));
+                self.write(VmCommand::Push(Mem::That, 0));
+            }
+            Term::Call(call) => {
+                if let Some((name, signature)) = self.compile_subroutine_call(call) {
+                    if signature.return_type.is_none() {
+                        self.throw_error_at(CompilationError::VoidValue(name), call.span);
+                    }
+                }
+            }
+            Term::Paren(expr, _) => self.compile_expression(expr),
+            Term::Unary(op, term, _) => {
+                self.compile_term(term);
+                self.write(match op {
+                    UnaryOp::Neg => VmCommand::Neg,
+                    UnaryOp::Not => VmCommand::Not,
+                });
+            }
+        }
+    }
+
+    // Makes sure the subroutine exists and is called the way it was declared
+    // Returns the signature of the subroutine if it could be found
+    fn check_call(
+        &mut self,
+        call: &SubroutineCall,
+        class_name: &str,
+        method: bool,
+    ) -> Option<&'a Signature> {
+        // Methods called on an int and the like are left to the TypeChecker
+        if !matches!(Type::from_name(class_name), Type::Class(_)) {
+            return None;
+        }
+        if !self.index.has_class(class_name) {
+            let span = call.receiver.as_ref().map_or(call.span, |r| r.span);
+            self.throw_error_at(CompilationError::UnknownClass(class_name.to_string()), span);
+            return None;
+        }
+        let name = &call.name.name;
+        let Some(signature) = self.index.get(class_name, name) else {
+            self.throw_error_at(
+                CompilationError::UnknownSubroutine {
+                    class: class_name.to_string(),
+                    name: name.clone(),
+                },
+                call.name.span,
+            );
+            return None;
+        };
+        let full_name = format!("{class_name}.{name}");
+        if (signature.kind == SubroutineKind::Method) != method {
+            let kind = signature.kind;
+            self.throw_error_at(
+                CompilationError::WrongCallKind {
+                    name: full_name,
+                    kind,
+                },
+                call.span,
+            );
+        } else if call.receiver.is_none() && self.subroutine_kind == SubroutineKind::Function {
+            self.throw_error_at(CompilationError::MethodInFunction(full_name), call.span);
+        } else if signature.params.len() != call.args.len() {
+            let expected = signature.params.len();
+            self.throw_error_at(
+                CompilationError::ArgumentCount {
+                    name: full_name,
+                    expected,
+                    found: call.args.len(),
+                },
+                call.span,
+            );
+        }
+        Some(signature)
+    }
+
+    // Numbered separately for each subroutine so they don't depend on what was compiled before
+    fn label(&self, name: &str, n: u16) -> String {
+        match self.labels {
+            LabelStyle::Scoped => format!("{}.{}${name}{n}", self.class_name, self.subroutine_name),
+            LabelStyle::Reference => format!("{name}{n}"),
+        }
+    }
+
+    fn write_constant(&mut self, t: &Token) {
+        match t {
+            Token::Keyword(Keyword::True) => {
+                self.write(VmCommand::Push(Mem::Constant, 1));
+                self.write(VmCommand::Neg);
+            }
+            Token::Keyword(Keyword::False) | Token::Keyword(Keyword::Null) => {
+                self.write(VmCommand::Push(Mem::Constant, 0))
+            }
+            Token::Keyword(Keyword::This) => self.write(VmCommand::Push(Mem::Pointer, 0)),
+            Token::IntConstant(i) => self.write(VmCommand::Push(Mem::Constant, *i)),
+            Token::StringConstant(s) => {
+                self.write(VmCommand::Push(Mem::Constant, s.len() as i16));
+                self.write(VmCommand::Call(String::from("String.new"), 1));
+                for c in s.chars() {
+                    self.write(VmCommand::Push(Mem::Constant, c as i16));
+                    self.write(VmCommand::Call(String::from("String.appendChar"), 2));
+                }
+            }
+            _ => { /*only passing constants*/ }
+        }
+    }
+
+    // Operands are pushed left to right and the operator is applied once both are on the stack
+    fn compile_expression(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Term(term) => self.compile_term(term),
+            Expr::Binary { op, lhs, rhs, .. } => {
+                self.compile_expression(lhs);
+                self.compile_expression(rhs);
+                match op {
+                    BinaryOp::Mod => self.compile_modulo(),
+                    _ => self.write(binary_op_command(*op)),
+                }
+            }
+        }
+    }
+
+    // The course OS has no modulo, so x % y is x - (x / y) * y with x and y parked in temp
+    // Both are pushed again before either call, as the OS may use temp itself
+    fn compile_modulo(&mut self) {
+        self.write(VmCommand::Pop(Mem::Temp, 1));
+        self.write(VmCommand::Pop(Mem::Temp, 0));
+        for index in [0, 1, 0, 1] {
+            self.write(VmCommand::Push(Mem::Temp, index));
+        }
+        self.write(binary_op_command(BinaryOp::Div));
+        self.write(binary_op_command(BinaryOp::Mul));
+        self.write(VmCommand::Sub);
+    }
+}
+
+fn binary_op_command(op: BinaryOp) -> VmCommand {
+    match op {
+        BinaryOp::Add => VmCommand::Add,
+        BinaryOp::Sub => VmCommand::Sub,
+        BinaryOp::And => VmCommand::And,
+        BinaryOp::Or => VmCommand::Or,
+        BinaryOp::Eq => VmCommand::Compare(Eq),
+        BinaryOp::Gt => VmCommand::Compare(GT),
+        BinaryOp::Lt => VmCommand::Compare(LT),
+        BinaryOp::Mul => VmCommand::Call(String::from("Math.multiply"), 2),
+        BinaryOp::Div => VmCommand::Call(String::from("Math.divide"), 2),
+        // Compiled as more than one command, see compile_modulo
+        BinaryOp::Mod => unreachable!("modulo has no single VM command"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LabelStyle;
+    use crate::{CompilationEngine, Strictness, VmInterpreter};
+
+    #[test]
+    fn test_no_this_in_functions() {
+        let source = "class Main { field int x; field Main next; \
+                      method void draw() { return; } \
+                      method void ok() { let x = x + 1; do draw(); do next.draw(); return; } \
+                      function Main f() { let x = 1; do next.draw(); do draw(); return this; } }";
+        let errors: Vec<_> = CompilationEngine::new()
+            .with_type_check(Strictness::Off)
+            .compile_str(source)
+            .unwrap_err()
+            .into_iter()
+            .map(|d| (d.span.col, d.error.to_string()))
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                (
+                    170,
+                    String::from("field `x` can't be used in a function, there is no `this`")
+                ),
+                (
+                    180,
+                    String::from("field `next` can't be used in a function, there is no `this`")
+                ),
+                (
+                    196,
+                    String::from(
+                        "`Main.draw` is a method and can't be called from a function without an object"
+                    )
+                ),
+                (211, String::from("`this` can't be used in a function")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_modulo_only_needs_the_course_os() {
+        let source = "class Main { function int f(int x, int y) { return x % y; } \
+                      function int g(int x, int y, int z) { return x % (y % z); } }";
+        let program = CompilationEngine::new().compile_str(source).unwrap();
+        assert!(!program.to_string().contains("modulo"));
+        let mut vm = VmInterpreter::new();
+        vm.load(&program.class_name, &program.commands).unwrap();
+        // Truncated towards zero, the same as Math.divide
+        assert_eq!(vm.call("Main.f", &[17, 5]), Ok(2));
+        assert_eq!(vm.call("Main.f", &[-7, 4]), Ok(-3));
+        assert_eq!(vm.call("Main.f", &[7, -4]), Ok(3));
+        assert_eq!(vm.call("Main.g", &[17, 11, 7]), Ok(1));
+    }
+
+    #[test]
+    fn test_label_styles() {
+        let source = "class Main { function void a(boolean b) { if (b) { return; } return; } \
+                      function void c(boolean b) { while (b) { if (b) { let b = false; } } return; } }";
+        let labels = |style| {
+            let program = CompilationEngine::new()
+                .with_labels(style)
+                .compile_str(source)
+                .unwrap();
+            program
+                .to_string()
+                .lines()
+                .filter(|line| line.contains("goto") || line.starts_with("label"))
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+        // Counters restart in every subroutine
+        assert_eq!(
+            labels(LabelStyle::Scoped),
+            [
+                "if-goto Main.a$IF_FALSE0",
+                "goto Main.a$IF_END0",
+                "label Main.a$IF_FALSE0",
+                "label Main.a$IF_END0",
+                "label Main.c$WHILE_EXP0",
+                "if-goto Main.c$WHILE_END0",
+                "if-goto Main.c$IF_FALSE0",
+                "goto Main.c$IF_END0",
+                "label Main.c$IF_FALSE0",
+                "label Main.c$IF_END0",
+                "goto Main.c$WHILE_EXP0",
+                "label Main.c$WHILE_END0",
+            ]
+        );
+        assert_eq!(
+            labels(LabelStyle::Reference),
+            [
+                "if-goto IF_TRUE0",
+                "goto IF_FALSE0",
+                "label IF_TRUE0",
+                "label IF_FALSE0",
+                "label WHILE_EXP0",
+                "if-goto WHILE_END0",
+                "if-goto IF_TRUE0",
+                "goto IF_FALSE0",
+                "label IF_TRUE0",
+                "label IF_FALSE0",
+                "goto WHILE_EXP0",
+                "label WHILE_END0",
+            ]
+        );
+    }
+}
@@ -1,51 +1,390 @@
 use crate::{
-    symbol_table::*,
-    token_type::{TokenType, ValidToken},
-    tokenizer::Tokenizer,
-    tokens::{
-        Keyword::{self, *},
-        Token,
+    assembler::{assemble, to_hack},
+    ast::{
+        self, BinaryOp, Expr, Ident, Parameter, Statement, Subroutine, SubroutineCall,
+        SubroutineKind, Term, UnaryOp, VarDec,
     },
-    vm_writer::{CodeWriter, Comparison::*, MemSegment as Mem, VmCommand, VmWriter},
-    //xml_writer::XMLWriter,
+    code_generator::{CodeGenerator, LabelStyle, VmProgram},
+    diagnostics::{Diagnostic, Level},
+    error::Error,
+    flow_checker,
+    os_api::OS_CLASSES,
+    program_index::ProgramIndex,
+    symbol_table::Kind,
+    token_type::{binary_precedence, Expected, TokenType, ValidToken},
+    tokenizer::{Span, Tokenizer},
+    tokens::{Keyword::*, Token},
+    type_checker::{Strictness, TypeChecker},
+    usage_checker::UsageChecker,
+    vm_translator::VmTranslator,
+    vm_writer::{parse_vm, CodeWriter, VmWriter},
+    xml_writer::XMLWriter,
+};
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
 };
-use std::path::PathBuf;
 
+// Parses a Jack class into its syntax tree, then hands it off to the CodeGenerator
 pub struct CompilationEngine {
-    writer: VmWriter,
     tokenizer: Tokenizer,
-    class_name: String,
     curr_token: Option<Token>,
-    symbol_table: SymbolTable,
-    errors: Vec<(CompilationError, Option<Token>)>,
+    curr_span: Span,
+    prev_span: Span,
+    // What we are in the middle of parsing, innermost last
+    context: Vec<&'static str>,
+    diagnostics: Vec<Diagnostic>,
+    // Parse binary operators with conventional precedence instead of strictly left to right
+    precedence: bool,
+    emit: Vec<Emit>,
+    output: Output,
+    type_check: Strictness,
+    labels: LabelStyle,
+    // Signatures of every class seen so far, for checking calls between them
+    index: ProgramIndex,
+    // Every class compiled so far with Emit::Asm or Emit::Hack, written out together by write_program
+    asm: VmTranslator,
+    // Parse tree output, only while compiling with Emit::Xml
+    xml: Option<XMLWriter<Vec<u8>>>,
+    // Set after a syntax error until the next token is successfully consumed
+    recovering: bool,
+}
+
+// Returned once a syntax error has been reported so the parser can unwind
+// to the nearest statement or declaration and pick up from there
+#[derive(Debug)]
+struct SyntaxError;
+
+type ParseResult<T> = Result<T, SyntaxError>;
+
+// The files written for each compiled class
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Emit {
+    // Xxx.vm
+    Vm,
+    // XxxT.xml, every token as a flat list
+    TokensXml,
+    // Xxx.xml, the parse tree
+    Xml,
+    // One .asm for the whole program, see write_program
+    Asm,
+    // The same program assembled into a .hack file
+    Hack,
+}
+
+impl Emit {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "vm" => Some(Emit::Vm),
+            "tokens-xml" => Some(Emit::TokensXml),
+            "xml" => Some(Emit::Xml),
+            "asm" => Some(Emit::Asm),
+            "hack" => Some(Emit::Hack),
+            _ => None,
+        }
+    }
+}
+
+// Where the files for each class are written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Output {
+    // Next to the .jack file
+    SourceDir,
+    Dir(PathBuf),
+    // Everything one after another, for `-o -`
+    Stdout,
 }
 
 #[derive(Debug, Clone)]
 pub enum CompilationError {
-    DuplicateIdentifier,
-    UnexpectedToken,
-    InvalidInt,
-    UnrecognizedToken,
-    UndeclaredIdentifier,
-    UnexpectedEndofTokens,
+    DuplicateIdentifier(String),
+    UnexpectedToken {
+        expected: Expected,
+        found: Token,
+    },
+    InvalidInt(String),
+    UnrecognizedToken(char),
+    UnterminatedString,
+    UnterminatedComment,
+    UndeclaredIdentifier(String),
+    UnexpectedEndofTokens {
+        expected: Expected,
+    },
+    PrecedenceMismatch {
+        first: char,
+        then: char,
+    },
+    TypeMismatch {
+        expected: String,
+        found: String,
+    },
+    MethodOnPrimitive {
+        name: String,
+        var_type: String,
+    },
+    UnknownClass(String),
+    MissingReturn {
+        name: String,
+        return_type: Option<String>,
+    },
+    ReturnValueInVoid(String),
+    MissingReturnValue(String),
+    ConstructorReturn,
+    VoidValue(String),
+    FieldInFunction(String),
+    ThisInFunction,
+    MethodInFunction(String),
+    UnusedVariable {
+        name: String,
+        kind: Kind,
+    },
+    UninitializedVariable(String),
+    ShadowedVariable {
+        name: String,
+        kind: Kind,
+    },
+    UnknownSubroutine {
+        class: String,
+        name: String,
+    },
+    WrongCallKind {
+        name: String,
+        kind: SubroutineKind,
+    },
+    ArgumentCount {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+}
+
+impl CompilationError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            CompilationError::UnrecognizedToken(_) => "E0001",
+            CompilationError::InvalidInt(_) => "E0002",
+            CompilationError::UnexpectedToken { .. } => "E0003",
+            CompilationError::UnexpectedEndofTokens { .. } => "E0004",
+            CompilationError::UndeclaredIdentifier(_) => "E0005",
+            CompilationError::DuplicateIdentifier(_) => "E0006",
+            CompilationError::TypeMismatch { .. } => "E0007",
+            CompilationError::MethodOnPrimitive { .. } => "E0008",
+            CompilationError::UnknownSubroutine { .. } => "E0009",
+            CompilationError::WrongCallKind { .. } => "E0010",
+            CompilationError::ArgumentCount { .. } => "E0011",
+            CompilationError::UnknownClass(_) => "E0012",
+            CompilationError::MissingReturn { .. } => "E0013",
+            CompilationError::ReturnValueInVoid(_) => "E0014",
+            CompilationError::MissingReturnValue(_) => "E0015",
+            CompilationError::ConstructorReturn => "E0016",
+            CompilationError::VoidValue(_) => "E0017",
+            CompilationError::FieldInFunction(_) => "E0018",
+            CompilationError::ThisInFunction => "E0019",
+            CompilationError::MethodInFunction(_) => "E0020",
+            CompilationError::UnterminatedString => "E0021",
+            CompilationError::UnterminatedComment => "E0022",
+            CompilationError::PrecedenceMismatch { .. } => "W0001",
+            CompilationError::UnusedVariable { .. } => "W0002",
+            CompilationError::UninitializedVariable(_) => "W0003",
+            CompilationError::ShadowedVariable { .. } => "W0004",
+        }
+    }
+}
+
+impl Display for CompilationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompilationError::DuplicateIdentifier(name) => {
+                write!(f, "`{name}` is already declared in this scope")
+            }
+            CompilationError::UnexpectedToken { expected, found } => {
+                write!(f, "expected {expected}, found {}", found.describe())
+            }
+            CompilationError::InvalidInt(num) => {
+                write!(f, "integer constant `{num}` is out of range (0..=32767)")
+            }
+            CompilationError::UnrecognizedToken(c) => write!(f, "unrecognized character `{c}`"),
+            CompilationError::UnterminatedString => {
+                write!(
+                    f,
+                    "string constant is missing its closing `\"` on this line"
+                )
+            }
+            CompilationError::UnterminatedComment => {
+                write!(f, "comment is missing its closing `*/`")
+            }
+            CompilationError::UndeclaredIdentifier(name) => {
+                write!(f, "cannot find `{name}` in this scope")
+            }
+            CompilationError::UnexpectedEndofTokens { expected } => {
+                write!(f, "expected {expected}, found end of file")
+            }
+            CompilationError::PrecedenceMismatch { first, then } => write!(
+                f,
+                "`{then}` is applied after `{first}` since Jack evaluates strictly left to right, \
+                 add parentheses if `{then}` should bind tighter"
+            ),
+            CompilationError::TypeMismatch { expected, found } => {
+                write!(
+                    f,
+                    "mismatched types: expected `{expected}`, found `{found}`"
+                )
+            }
+            CompilationError::MethodOnPrimitive { name, var_type } => {
+                write!(f, "`{name}` is an `{var_type}`, which has no methods")
+            }
+            CompilationError::UnknownClass(name) => write!(f, "cannot find class `{name}`"),
+            CompilationError::MissingReturn {
+                name,
+                return_type: Some(t),
+            } => write!(
+                f,
+                "`{name}` has to return a value of type `{t}` on every path"
+            ),
+            CompilationError::MissingReturn {
+                name,
+                return_type: None,
+            } => write!(f, "`{name}` has to end with a return statement"),
+            CompilationError::ReturnValueInVoid(name) => {
+                write!(f, "`{name}` is void but returns a value")
+            }
+            CompilationError::MissingReturnValue(t) => {
+                write!(f, "this return needs a value of type `{t}`")
+            }
+            CompilationError::ConstructorReturn => {
+                write!(f, "constructors have to `return this;`")
+            }
+            CompilationError::VoidValue(name) => {
+                write!(f, "`{name}` returns void, so there's no value to use")
+            }
+            CompilationError::FieldInFunction(name) => {
+                write!(
+                    f,
+                    "field `{name}` can't be used in a function, there is no `this`"
+                )
+            }
+            CompilationError::ThisInFunction => {
+                write!(f, "`this` can't be used in a function")
+            }
+            CompilationError::UnusedVariable { name, kind } => {
+                write!(f, "{} `{name}` is never read", kind.describe())
+            }
+            CompilationError::UninitializedVariable(name) => {
+                write!(f, "`{name}` is read before anything is assigned to it")
+            }
+            CompilationError::ShadowedVariable { name, kind } => write!(
+                f,
+                "local variable `{name}` shadows a {} of the same name",
+                kind.describe()
+            ),
+            CompilationError::MethodInFunction(name) => write!(
+                f,
+                "`{name}` is a method and can't be called from a function without an object"
+            ),
+            CompilationError::UnknownSubroutine { class, name } => {
+                write!(f, "class `{class}` has no subroutine named `{name}`")
+            }
+            CompilationError::WrongCallKind { name, kind } => match kind {
+                SubroutineKind::Method => {
+                    write!(f, "`{name}` is a method and has to be called on an object")
+                }
+                _ => write!(f, "`{name}` is a {kind} and can't be called on an object"),
+            },
+            CompilationError::ArgumentCount {
+                name,
+                expected,
+                found,
+            } => {
+                let s = if *expected == 1 { "" } else { "s" };
+                let was = if *found == 1 { "was" } else { "were" };
+                write!(
+                    f,
+                    "`{name}` takes {expected} argument{s} but {found} {was} supplied"
+                )
+            }
+        }
+    }
+}
+
+impl Default for CompilationEngine {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-use crate::token_type::TokenType::*;
 impl CompilationEngine {
+    // Starts out knowing the signatures of the Jack OS
     pub fn new() -> Self {
-        CompilationEngine {
-            writer: VmWriter::default(),
+        let mut engine = CompilationEngine {
             tokenizer: Tokenizer::default(),
-            class_name: String::new(),
-            symbol_table: SymbolTable::default(),
             curr_token: None,
-            errors: vec![],
+            curr_span: Span::default(),
+            prev_span: Span::default(),
+            context: vec![],
+            diagnostics: vec![],
+            precedence: false,
+            emit: vec![Emit::Vm],
+            output: Output::SourceDir,
+            type_check: Strictness::Lenient,
+            labels: LabelStyle::Scoped,
+            index: ProgramIndex::default(),
+            asm: VmTranslator::new(),
+            xml: None,
+            recovering: false,
+        };
+        for class in OS_CLASSES {
+            engine.index_str(class);
         }
+        engine
+    }
+
+    pub fn with_precedence(mut self, precedence: bool) -> Self {
+        self.precedence = precedence;
+        self
+    }
+
+    pub fn with_emit(mut self, emit: Vec<Emit>) -> Self {
+        self.emit = emit;
+        self
+    }
+
+    pub fn with_output(mut self, output: Output) -> Self {
+        self.output = output;
+        self
+    }
+
+    pub fn with_labels(mut self, labels: LabelStyle) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    pub fn with_type_check(mut self, strictness: Strictness) -> Self {
+        self.type_check = strictness;
+        self
     }
 
     pub fn throw_error(&mut self, err: CompilationError) {
-        let token = self.curr_token.as_ref();
-        self.errors.push((err, Option::<&Token>::cloned(token)));
+        self.throw_error_at(err, self.curr_span);
+    }
+
+    // For errors about a token we have already consumed, like an identifier's name
+    pub fn throw_error_at(&mut self, error: CompilationError, span: Span) {
+        self.report(Level::Error, error, span);
+    }
+
+    pub fn warn_at(&mut self, warning: CompilationError, span: Span) {
+        self.report(Level::Warning, warning, span);
+    }
+
+    fn report(&mut self, level: Level, error: CompilationError, span: Span) {
+        self.diagnostics.push(Diagnostic {
+            level,
+            error,
+            span,
+            context: self.context.last().copied(),
+        });
     }
 
     pub fn curr_token_is<T: ValidToken + PartialEq<Token>>(&self, other: T) -> bool {
@@ -56,451 +395,1157 @@ impl CompilationEngine {
         }
     }
 
-    pub fn compile(&mut self, file: PathBuf) -> Result<(), &[(CompilationError, Option<Token>)]> {
-        let filename = file.as_path().to_str().expect("could not convert to str");
-        let tokenizer = Tokenizer::new(std::fs::read_to_string(&file).expect("failed to read"));
+    // Ok holds any warnings, a class with errors is returned as Error::Compilation with every diagnostic
+    pub fn compile(&mut self, file: PathBuf) -> Result<&[Diagnostic], Error> {
+        let source = std::fs::read_to_string(&file).map_err(|e| Error::io(&file, e))?;
 
-        self.writer = VmWriter::new(filename);
-        self.tokenizer = tokenizer;
-        self.curr_token = self.tokenizer.advance();
-        self.symbol_table = SymbolTable::default();
+        // Tokens are written before parsing so they are available even if the class doesn't parse
+        if self.emit.contains(&Emit::TokensXml) {
+            let stem = file
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or_default();
+            let (path, out) = self.create_output(&file.with_file_name(format!("{stem}T.xml")))?;
+            let mut writer = XMLWriter::new(out);
+            writer.write_tokens(Tokenizer::new(source.clone()));
+            writer.flush().map_err(|e| Error::io(&path, e))?;
+        }
 
-        self.construct_class();
-        self.writer.flush();
+        // The parse tree is built up in memory and only written out if the class parsed,
+        // a partial tree would just be reported as a mismatch by the course's comparer
+        self.xml = self
+            .emit
+            .contains(&Emit::Xml)
+            .then(|| XMLWriter::new(vec![]));
+        let class = self.parse(source);
+        let parsed = class.is_ok() && self.diagnostics.iter().all(|d| d.level != Level::Error);
+        if let Some(mut xml) = self.xml.take().filter(|_| parsed) {
+            let (path, mut out) = self.create_output(&file.with_extension("xml"))?;
+            xml.flush()
+                .and_then(|_| out.write_all(&xml.into_inner()))
+                .and_then(|_| out.flush())
+                .map_err(|e| Error::io(&path, e))?;
+        }
 
-        let errors = &self.errors;
-        if !errors.is_empty() {
-            Err(errors)
+        if self.emit.contains(&Emit::Vm) || self.links() {
+            if let Some(program) = self.generate(class) {
+                if self.emit.contains(&Emit::Vm) {
+                    let (path, out) = self.create_output(&file.with_extension("vm"))?;
+                    let mut writer = VmWriter::new(out);
+                    for command in &program.commands {
+                        writer.write(command);
+                    }
+                    writer.flush().map_err(|e| Error::io(&path, e))?;
+                }
+                if self.links() {
+                    self.asm.translate(&program.class_name, &program.commands);
+                }
+            }
+        } else {
+            self.diagnostics.sort_by_key(|d| d.span.start);
+        }
+
+        if self.diagnostics.iter().any(|d| d.level == Level::Error) {
+            Err(Error::Compilation(self.diagnostics.clone()))
         } else {
-            Ok(())
+            Ok(&self.diagnostics)
         }
     }
 
-    fn consume<T: ValidToken + PartialEq<Token> + Copy>(&mut self, requested: T) -> Token {
-        if self.curr_token.is_none() {
-            self.throw_error(CompilationError::UnexpectedEndofTokens);
-        } else if !self.curr_token_is(requested) {
-            self.throw_error(CompilationError::UnexpectedToken);
+    // Opens one of the files for a class, `path` being where it goes by default
+    // Returns where it actually ended up for reporting errors
+    fn create_output(&self, path: &Path) -> Result<(PathBuf, Box<dyn Write>), Error> {
+        let path = match &self.output {
+            Output::Stdout => return Ok((PathBuf::from("<stdout>"), Box::new(io::stdout()))),
+            Output::Dir(dir) => dir.join(path.file_name().unwrap_or_default()),
+            Output::SourceDir => path.to_path_buf(),
+        };
+        let file = File::create(&path).map_err(|e| Error::io(&path, e))?;
+        Ok((path, Box::new(BufWriter::new(file))))
+    }
+
+    // Already compiled VM code that goes into the .asm alongside the program, e.g. the OS
+    // Statics are named after the file, the same as for a compiled class
+    pub fn translate_vm_files(&mut self, files: &[PathBuf]) -> Result<(), Error> {
+        for file in files {
+            let source = std::fs::read_to_string(file).map_err(|e| Error::io(file, e))?;
+            let commands = parse_vm(&source).map_err(|error| Error::Syntax {
+                path: file.clone(),
+                errors: vec![error],
+            })?;
+            let class_name = file
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or_default();
+            self.asm.translate(class_name, &commands);
         }
-        let mut token = self.tokenizer.advance();
-        std::mem::swap(&mut self.curr_token, &mut token);
-        // return the last token in case it's wanted
-        // using it is situational, and if it's not needed essentially discards it anyway
-        token.unwrap_or(Token::Symbol('?'))
+        Ok(())
     }
 
-    fn construct_class(&mut self) {
-        self.consume(Class);
-        if let Token::Identifier(name) = self.consume(TokenType::Name) {
-            self.class_name = name;
+    // Whether the classes are put together into a single program
+    pub fn links(&self) -> bool {
+        self.emit.contains(&Emit::Asm) || self.emit.contains(&Emit::Hack)
+    }
+
+    // Everything translated so far as a single program, both as assembly and
+    // as machine code, and starts over
+    // Errors are reported against `path`, where the .asm would have gone
+    pub fn link(&mut self, path: &Path) -> Result<(String, Vec<u16>), Error> {
+        let translator = std::mem::take(&mut self.asm);
+        let undefined = translator.undefined();
+        if !undefined.is_empty() {
+            return Err(Error::Link {
+                path: path.to_path_buf(),
+                undefined,
+            });
+        }
+        let asm = translator.finish();
+        let words = assemble(&asm).map_err(|errors| Error::Syntax {
+            path: path.to_path_buf(),
+            errors,
+        })?;
+        Ok((asm, words))
+    }
+
+    // Writes out the linked program for each of Emit::Asm and Emit::Hack,
+    // `path` being where it goes without the extension
+    pub fn write_program(&mut self, path: &Path) -> Result<(), Error> {
+        let asm_path = path.with_extension("asm");
+        let (asm, words) = self.link(&asm_path)?;
+        let mut outputs = vec![];
+        if self.emit.contains(&Emit::Asm) {
+            outputs.push((asm_path, asm));
         }
-        self.consume('{');
-        while self.curr_token_is(TokenType::ClassVarDec) {
-            self.handle_class_var_dec();
+        if self.emit.contains(&Emit::Hack) {
+            outputs.push((path.with_extension("hack"), to_hack(&words)));
         }
-        while self.curr_token_is(TokenType::SubroutineDec) {
-            self.handle_subroutine_dec();
+        for (path, contents) in outputs {
+            let (path, mut out) = self.create_output(&path)?;
+            out.write_all(contents.as_bytes())
+                .and_then(|_| out.flush())
+                .map_err(|e| Error::io(&path, e))?;
         }
-        self.consume('}');
+        Ok(())
     }
 
-    fn handle_class_var_dec(&mut self) {
-        // validate syntax and bind relevant elements to variables
-        if let (Token::Keyword(k @ (Static | Field)), type_of, Token::Identifier(name)) = (
-            self.consume(TokenType::ClassVarDec),
-            self.consume(TokenType::Type),
-            self.consume(TokenType::Name),
-        ) {
-            let kind = if k == Static {
-                Kind::Static
-            } else {
-                Kind::Field
-            };
-            let type_str = type_of.as_type();
-            // Add the newly declared variable to the symbol table
-            self.symbol_table
-                .define(kind, &type_str, name)
-                .map_err(|e| self.throw_error(e))
-                .unwrap();
-
-            // Support multiple declarations of the same type before a semicolon
-            while self.curr_token_is(',') {
-                self.consume(',');
-                if let Token::Identifier(name) = self.consume(TokenType::Name) {
-                    self.symbol_table
-                        .define(kind, &type_str, name)
-                        .map_err(|e| self.throw_error(e))
-                        .unwrap();
-                }
-            }
-            self.consume(';');
+    // Hand written assembly straight to machine code, next to the .asm by default
+    pub fn assemble_file(&self, file: &Path) -> Result<(), Error> {
+        let source = std::fs::read_to_string(file).map_err(|e| Error::io(file, e))?;
+        let words = assemble(&source).map_err(|errors| Error::Syntax {
+            path: file.to_path_buf(),
+            errors,
+        })?;
+        let (path, mut out) = self.create_output(&file.with_extension("hack"))?;
+        out.write_all(to_hack(&words).as_bytes())
+            .and_then(|_| out.flush())
+            .map_err(|e| Error::io(&path, e))
+    }
+
+    // Adds the subroutines of each class to the index ahead of compiling any of them
+    pub fn index_files(&mut self, files: &[PathBuf]) -> Result<(), Error> {
+        for file in files {
+            let source = std::fs::read_to_string(file).map_err(|e| Error::io(file, e))?;
+            self.index_str(&source);
+        }
+        Ok(())
+    }
+
+    // Syntax errors are left to be reported when the class is compiled
+    pub fn index_str(&mut self, source: &str) {
+        if let Ok(class) = self.parse(source.to_string()) {
+            self.index.add_class(&class);
         }
+        self.diagnostics.clear();
     }
 
-    fn handle_subroutine_dec(&mut self) {
-        // Clear the subroutine symbol table and reset the arg/var counts
-        self.symbol_table.start_subroutine();
+    // Compiles a single class without touching the file system, the emit options are ignored
+    pub fn compile_str(&mut self, source: &str) -> Result<VmProgram, Vec<Diagnostic>> {
+        let class = self.parse(source.to_string());
+        self.generate(class).ok_or_else(|| self.diagnostics.clone())
+    }
 
-        // Validate syntax and bind relevant elements to variables
-        if let (
-            Token::Keyword(func_type @ (Constructor | Function | Method)),
-            _return_type,
-            Token::Identifier(name),
-        ) = (
-            self.consume(TokenType::SubroutineDec),
-            self.consume(TokenType::ReturnType),
-            self.consume(TokenType::Name),
-        ) {
-            // Jack methods include "this" as their first unspoken argument
-            if func_type == Method {
-                self.symbol_table
-                    .define(Kind::Arg, &self.class_name, String::from("this"))
-                    .map_err(|e| self.throw_error(e))
-                    .unwrap();
-            }
-            self.consume('(');
-            // Add 0 or more arguments to the symbol table
-            self.handle_parameter_list();
-            self.consume(')');
-            self.handle_subroutine_body(func_type, name);
+    fn parse(&mut self, source: String) -> ParseResult<ast::Class> {
+        self.tokenizer = Tokenizer::new(source);
+        self.curr_token = None;
+        self.advance();
+        self.context.clear();
+        self.diagnostics.clear();
+        self.recovering = false;
+
+        let class = self.construct_class();
+        for (err, span) in self.tokenizer.take_errors() {
+            self.throw_error_at(err, span);
         }
+        class
     }
 
-    fn handle_parameter_list(&mut self) {
-        while !self.curr_token_is(')') {
-            if let (type_of, Token::Identifier(name)) =
-                (self.consume(TokenType::Type), self.consume(TokenType::Name))
-            {
-                self.symbol_table
-                    .define(Kind::Arg, &type_of.as_type(), name)
-                    .map_err(|e| self.throw_error(e))
-                    .unwrap();
-            }
-            if self.curr_token_is(',') {
-                self.consume(',');
+    // Only returns the program if the class compiled without errors
+    fn generate(&mut self, class: ParseResult<ast::Class>) -> Option<VmProgram> {
+        // Don't bother generating code for a class that didn't parse
+        let program = match class {
+            Ok(class) if self.diagnostics.iter().all(|d| d.level != Level::Error) => {
+                // The class is indexed again in case it changed since the pre-pass
+                self.index.add_class(&class);
+                let (commands, diagnostics) = CodeGenerator::new(&self.index)
+                    .with_labels(self.labels)
+                    .generate(&class);
+                self.diagnostics.extend(diagnostics);
+                self.diagnostics
+                    .extend(TypeChecker::new(self.type_check, &self.index).check(&class));
+                self.diagnostics.extend(flow_checker::check(&class));
+                self.diagnostics.extend(UsageChecker::new().check(&class));
+                Some(VmProgram {
+                    class_name: class.name.name,
+                    commands,
+                    warnings: vec![],
+                })
             }
+            _ => None,
+        };
+        self.diagnostics.sort_by_key(|d| d.span.start);
+
+        if self.diagnostics.iter().any(|d| d.level == Level::Error) {
+            None
+        } else {
+            program.map(|program| VmProgram {
+                warnings: self.diagnostics.clone(),
+                ..program
+            })
         }
     }
 
-    fn handle_subroutine_body(&mut self, func_type: Keyword, name: String) {
-        self.consume('{');
+    // Moves to the next token, keeping track of where it was found
+    // and returns the one we were on
+    fn advance(&mut self) -> Option<Token> {
+        let next = match self.tokenizer.advance() {
+            Some((token, span)) => {
+                self.prev_span = self.curr_span;
+                self.curr_span = span;
+                Some(token)
+            }
+            None => {
+                self.prev_span = self.curr_span;
+                self.curr_span = self.tokenizer.eof_span();
+                None
+            }
+        };
+        std::mem::replace(&mut self.curr_token, next)
+    }
 
-        // Add 0 or more local variables to the symbol table
-        while self.curr_token_is(Keyword::Var) {
-            self.handle_var_dec();
+    // Only moves past the current token if it is the one requested
+    // Otherwise the error is reported and the parser unwinds to the nearest point it can resynchronize
+    fn consume<T>(&mut self, requested: T) -> ParseResult<Token>
+    where
+        T: ValidToken + PartialEq<Token> + Copy + Into<Expected>,
+    {
+        if self.curr_token_is(requested) {
+            if let Some(token) = self.advance() {
+                if let Some(xml) = self.xml.as_mut() {
+                    xml.write(&token);
+                }
+                self.recovering = false;
+                return Ok(token);
+            }
         }
+        let expected = requested.into();
+        let err = match &self.curr_token {
+            None => CompilationError::UnexpectedEndofTokens { expected },
+            Some(t) => CompilationError::UnexpectedToken {
+                expected,
+                found: t.clone(),
+            },
+        };
+        // Anything that goes wrong before we get back on track is just fallout from the first error
+        if !self.recovering {
+            self.throw_error(err);
+            self.recovering = true;
+        }
+        Err(SyntaxError)
+    }
 
-        // Declare function now that the symbol table is complete
-        self.writer.write(VmCommand::Function(
-            &format!("{}.{}", self.class_name, name),
-            self.symbol_table.var_count(Kind::Var),
-        ));
-
-        if func_type == Constructor {
-            // Constructors require allocating enough memory for all fields
-            self.writer.write(VmCommand::Push(
-                Mem::Constant,
-                self.symbol_table.var_count(Kind::Field),
-            ));
-            self.writer.write(VmCommand::Call("Memory.alloc", 1));
-            self.writer.write(VmCommand::Pop(Mem::Pointer, 0));
-        } else if func_type == Method {
-            // Methods require a pointer to the current object
-            self.writer.write(VmCommand::Push(Mem::Argument, 0));
-            self.writer.write(VmCommand::Pop(Mem::Pointer, 0));
-        }
-        self.handle_statements();
-        self.consume('}');
-    }
-
-    fn handle_var_dec(&mut self) {
-        if let (Token::Keyword(_k @ Var), type_of, Token::Identifier(name)) = (
-            self.consume(Var),
-            self.consume(TokenType::Type),
-            self.consume(TokenType::Name),
-        ) {
-            self.symbol_table
-                .define(Kind::Var, &type_of.as_type(), name)
-                .map_err(|e| self.throw_error(e))
-                .unwrap();
-            while self.curr_token_is(',') {
-                self.consume(',');
-                if let Token::Identifier(name) = self.consume(TokenType::Name) {
-                    self.symbol_table
-                        .define(Kind::Var, &type_of.as_type(), name)
-                        .map_err(|e| self.throw_error(e))
-                        .unwrap();
+    // Skips to the end of the broken statement or declaration,
+    // or to wherever the next one starts if there is no ';' in between
+    // `depth` is how much context to keep, everything the broken part pushed is dropped
+    fn synchronize(&mut self, depth: usize) {
+        self.context.truncate(depth);
+        while let Some(token) = &self.curr_token {
+            match token {
+                Token::Symbol(';') => {
+                    self.advance();
+                    return;
+                }
+                Token::Symbol('}') => return,
+                // Whole blocks are skipped so that their closing brace isn't mistaken for ours
+                Token::Symbol('{') => self.skip_block(),
+                Token::Keyword(k)
+                    if *k == TokenType::Statement
+                        || *k == TokenType::SubroutineDec
+                        || *k == TokenType::ClassVarDec
+                        || *k == Var =>
+                {
+                    return
+                }
+                _ => {
+                    self.advance();
                 }
             }
-            self.consume(';');
         }
     }
 
-    fn handle_statements(&mut self) {
-        while self.curr_token_is(TokenType::Statement) {
-            match self.curr_token.as_ref() {
-                Some(Token::Keyword(Let)) => self.handle_let(),
-                Some(Token::Keyword(If)) => self.handle_if(),
-                Some(Token::Keyword(While)) => self.handle_while(),
-                Some(Token::Keyword(Do)) => self.handle_do(),
-                Some(Token::Keyword(Return)) => self.handle_return(),
-                _ => break,
+    // Skips past a block and everything nested in it, starting from its opening '{'
+    fn skip_block(&mut self) {
+        let mut depth = 0;
+        while let Some(token) = self.advance() {
+            match token {
+                Token::Symbol('{') => depth += 1,
+                Token::Symbol('}') if depth == 1 => return,
+                Token::Symbol('}') => depth -= 1,
+                _ => {}
             }
         }
     }
 
-    fn handle_let(&mut self) {
-        self.consume(Let);
-        if let Token::Identifier(name) = self.consume(TokenType::Name) {
-            let (mut seg, mut id) = if let Some(entry) = self.symbol_table.get(&name) {
-                (
-                    match entry.get_kind() {
-                        Kind::Static => Mem::Static,
-                        Kind::Field => Mem::This,
-                        Kind::Arg => Mem::Argument,
-                        Kind::Var => Mem::Local,
-                    },
-                    entry.get_id(),
-                )
-            } else {
-                self.throw_error(CompilationError::UndeclaredIdentifier);
-                (Mem::Constant, 0)
-            };
-            let arr = if self.curr_token_is('[') {
-                self.consume('[');
-                self.handle_expression();
-                self.consume(']');
-                self.writer.write(VmCommand::Push(seg, id));
-                self.writer.write(VmCommand::Add);
-                (seg, id) = (Mem::That, 0);
-                true
+    // Skips to the next class member when a declaration is too broken to carry on with
+    fn synchronize_class(&mut self, depth: usize) {
+        self.context.truncate(depth);
+        while !(self.curr_token.is_none()
+            || self.curr_token_is(TokenType::SubroutineDec)
+            || self.curr_token_is(TokenType::ClassVarDec))
+        {
+            self.advance();
+        }
+    }
+
+    fn open_tag(&mut self, tag: &str) {
+        if let Some(xml) = self.xml.as_mut() {
+            xml.open(tag);
+        }
+    }
+
+    fn close_tag(&mut self, tag: &str) {
+        if let Some(xml) = self.xml.as_mut() {
+            xml.close(tag);
+        }
+    }
+
+    fn consume_ident(&mut self) -> ParseResult<Ident> {
+        let name = match self.consume(TokenType::Name)? {
+            Token::Identifier(name) => name,
+            _ => String::new(),
+        };
+        Ok(Ident {
+            name,
+            span: self.prev_span,
+        })
+    }
+
+    fn consume_type(&mut self) -> ParseResult<ast::Type> {
+        let token = self.consume(TokenType::Type)?;
+        Ok(ast::Type::from_token(&token).unwrap_or(ast::Type::Class(String::new())))
+    }
+
+    fn construct_class(&mut self) -> ParseResult<ast::Class> {
+        self.context.push("class declaration");
+        self.open_tag("class");
+        let start = self.curr_span;
+        self.consume(Class)?;
+        let name = self.consume_ident()?;
+        self.consume('{')?;
+        let depth = self.context.len();
+        let mut vars = vec![];
+        let mut subroutines = vec![];
+        while !(self.curr_token.is_none() || self.curr_token_is('}')) {
+            let member = if self.curr_token_is(TokenType::ClassVarDec) {
+                // Class variables have to be declared before any subroutines
+                if !subroutines.is_empty() && !self.recovering {
+                    let found = self.curr_token.clone().unwrap_or(Token::Symbol('?'));
+                    self.throw_error(CompilationError::UnexpectedToken {
+                        expected: Expected::Type(TokenType::SubroutineDec),
+                        found,
+                    });
+                }
+                self.handle_class_var_dec().map(|dec| vars.push(dec))
+            } else if self.curr_token_is(TokenType::SubroutineDec) {
+                self.handle_subroutine_dec().map(|s| subroutines.push(s))
             } else {
-                false
+                // Anything else is reported as the class not being closed where it should be
+                self.consume('}').map(|_| ())
             };
-            self.consume('=');
-            self.handle_expression();
-            if arr {
-                self.writer.write(VmCommand::Pop(Mem::Temp, 0));
-                self.writer.write(VmCommand::Pop(Mem::Pointer, 1));
-                self.writer.write(VmCommand::Push(Mem::Temp, 0));
+            if member.is_err() {
+                self.synchronize_class(depth);
             }
-            self.writer.write(VmCommand::Pop(seg, id));
-            self.consume(';');
         }
+        self.consume('}')?;
+        self.close_tag("class");
+        self.context.pop();
+        Ok(ast::Class {
+            name,
+            vars,
+            subroutines,
+            span: start.to(self.prev_span),
+        })
     }
 
-    fn handle_while(&mut self) {
-        self.consume(While);
-        self.consume('(');
+    fn handle_class_var_dec(&mut self) -> ParseResult<ast::ClassVarDec> {
+        self.context.push("class variable declaration");
+        self.open_tag("classVarDec");
+        let start = self.curr_span;
+        let kind = if self.consume(TokenType::ClassVarDec)? == Static {
+            Kind::Static
+        } else {
+            Kind::Field
+        };
+        let var_type = self.consume_type()?;
+        let mut names = vec![self.consume_ident()?];
 
-        let start_label = self.writer.generate_label("while");
-        let end_label = self.writer.generate_label("while");
+        // Support multiple declarations of the same type before a semicolon
+        while self.curr_token_is(',') {
+            self.consume(',')?;
+            names.push(self.consume_ident()?);
+        }
+        self.consume(';')?;
+        self.close_tag("classVarDec");
+        self.context.pop();
+        Ok(ast::ClassVarDec {
+            kind,
+            var_type,
+            names,
+            span: start.to(self.prev_span),
+        })
+    }
 
-        // Place the starting label just prior to evaluating the condition
-        self.writer.write(VmCommand::Label(&start_label));
-        self.handle_expression();
+    fn handle_subroutine_dec(&mut self) -> ParseResult<Subroutine> {
+        self.context.push("subroutine declaration");
+        self.open_tag("subroutineDec");
+        let start = self.curr_span;
+        let kind = match self.consume(TokenType::SubroutineDec)? {
+            Token::Keyword(Constructor) => SubroutineKind::Constructor,
+            Token::Keyword(Method) => SubroutineKind::Method,
+            _ => SubroutineKind::Function,
+        };
+        // void is the only return type that isn't also a value type
+        let return_type = ast::Type::from_token(&self.consume(TokenType::ReturnType)?);
+        let name = self.consume_ident()?;
+        self.consume('(')?;
+        let params = self.handle_parameter_list()?;
+        self.consume(')')?;
+        let (vars, statements) = self.handle_subroutine_body()?;
+        self.close_tag("subroutineDec");
+        self.context.pop();
+        Ok(Subroutine {
+            kind,
+            return_type,
+            name,
+            params,
+            vars,
+            statements,
+            span: start.to(self.prev_span),
+        })
+    }
 
-        // Bypass loop if negated condition is true
-        self.writer.write(VmCommand::Not);
-        self.writer.write(VmCommand::IfGoto(&end_label));
-        self.consume(')');
-        self.consume('{');
+    fn handle_parameter_list(&mut self) -> ParseResult<Vec<Parameter>> {
+        self.context.push("parameter list");
+        self.open_tag("parameterList");
+        let mut params = vec![];
+        if !self.curr_token_is(')') {
+            loop {
+                let var_type = self.consume_type()?;
+                let name = self.consume_ident()?;
+                params.push(Parameter { var_type, name });
+                if !self.curr_token_is(',') {
+                    break;
+                }
+                self.consume(',')?;
+            }
+        }
+        self.close_tag("parameterList");
+        self.context.pop();
+        Ok(params)
+    }
 
-        // Inside loop and jump to start
-        self.handle_statements();
-        self.writer.write(VmCommand::Goto(&start_label));
-        self.consume('}');
+    fn handle_subroutine_body(&mut self) -> ParseResult<(Vec<VarDec>, Vec<Statement>)> {
+        self.context.push("subroutine body");
+        self.open_tag("subroutineBody");
+        self.consume('{')?;
 
-        // Label at the end of loop
-        self.writer.write(VmCommand::Label(&end_label));
+        // 0 or more local variable declarations come before any statements
+        let depth = self.context.len();
+        let mut vars = vec![];
+        while self.curr_token_is(Var) {
+            match self.handle_var_dec() {
+                Ok(dec) => vars.push(dec),
+                Err(_) => self.synchronize(depth),
+            }
+        }
+        let statements = self.handle_statements();
+        self.consume('}')?;
+        self.close_tag("subroutineBody");
+        self.context.pop();
+        Ok((vars, statements))
     }
 
-    fn handle_if(&mut self) {
-        self.consume(If);
+    fn handle_var_dec(&mut self) -> ParseResult<VarDec> {
+        self.context.push("variable declaration");
+        self.open_tag("varDec");
+        let start = self.curr_span;
+        self.consume(Var)?;
+        let var_type = self.consume_type()?;
+        let mut names = vec![self.consume_ident()?];
+        while self.curr_token_is(',') {
+            self.consume(',')?;
+            names.push(self.consume_ident()?);
+        }
+        self.consume(';')?;
+        self.close_tag("varDec");
+        self.context.pop();
+        Ok(VarDec {
+            var_type,
+            names,
+            span: start.to(self.prev_span),
+        })
+    }
 
-        self.consume('(');
-        self.handle_expression();
-        self.consume(')');
+    // Broken statements are skipped so that the rest of the block is still checked
+    fn handle_statements(&mut self) -> Vec<Statement> {
+        self.open_tag("statements");
+        let depth = self.context.len();
+        let mut statements = vec![];
+        while self.curr_token_is(TokenType::Statement) {
+            let statement = match self.curr_token.as_ref() {
+                Some(Token::Keyword(Let)) => self.handle_let(),
+                Some(Token::Keyword(If)) => self.handle_if(),
+                Some(Token::Keyword(While)) => self.handle_while(),
+                Some(Token::Keyword(Do)) => self.handle_do(),
+                Some(Token::Keyword(Return)) => self.handle_return(),
+                _ => break,
+            };
+            match statement {
+                Ok(statement) => statements.push(statement),
+                Err(_) => self.synchronize(depth),
+            }
+        }
+        self.close_tag("statements");
+        statements
+    }
 
-        // Negate for simpler if-goto
-        self.writer.write(VmCommand::Not);
+    fn handle_let(&mut self) -> ParseResult<Statement> {
+        self.context.push("let statement");
+        self.open_tag("letStatement");
+        let start = self.curr_span;
+        self.consume(Let)?;
+        let target = self.consume_ident()?;
+        let index = if self.curr_token_is('[') {
+            self.consume('[')?;
+            let index = self.handle_expression()?;
+            self.consume(']')?;
+            Some(index)
+        } else {
+            None
+        };
+        self.consume('=')?;
+        let value = self.handle_expression()?;
+        self.consume(';')?;
+        self.close_tag("letStatement");
+        self.context.pop();
+        Ok(Statement::Let {
+            target,
+            index,
+            value,
+            span: start.to(self.prev_span),
+        })
+    }
 
-        let label1 = self.writer.generate_label("if");
-        let label2 = self.writer.generate_label("if");
+    fn handle_while(&mut self) -> ParseResult<Statement> {
+        self.context.push("while statement");
+        self.open_tag("whileStatement");
+        let start = self.curr_span;
+        self.consume(While)?;
+        self.consume('(')?;
+        let condition = self.handle_expression()?;
+        self.consume(')')?;
+        self.consume('{')?;
+        let body = self.handle_statements();
+        self.consume('}')?;
+        self.close_tag("whileStatement");
+        self.context.pop();
+        Ok(Statement::While {
+            condition,
+            body,
+            span: start.to(self.prev_span),
+        })
+    }
 
-        self.writer.write(VmCommand::IfGoto(&label1));
+    fn handle_if(&mut self) -> ParseResult<Statement> {
+        self.context.push("if statement");
+        self.open_tag("ifStatement");
+        let start = self.curr_span;
+        self.consume(If)?;
 
-        self.consume('{');
-        self.handle_statements();
-        self.consume('}');
+        self.consume('(')?;
+        let condition = self.handle_expression()?;
+        self.consume(')')?;
 
-        self.writer.write(VmCommand::Goto(&label2));
-        self.writer.write(VmCommand::Label(&label1));
+        self.consume('{')?;
+        let then = self.handle_statements();
+        self.consume('}')?;
 
-        if self.curr_token_is(Else) {
-            self.consume(Else);
+        let otherwise = if self.curr_token_is(Else) {
+            self.consume(Else)?;
+            // `else if` is allowed without wrapping the inner if in braces
             if self.curr_token_is(If) {
-                self.handle_if();
+                Some(vec![self.handle_if()?])
             } else {
-                self.consume('{');
-                self.handle_statements();
-                self.consume('}');
+                self.consume('{')?;
+                let otherwise = self.handle_statements();
+                self.consume('}')?;
+                Some(otherwise)
             }
-        }
+        } else {
+            None
+        };
+        self.close_tag("ifStatement");
+        self.context.pop();
+        Ok(Statement::If {
+            condition,
+            then,
+            otherwise,
+            span: start.to(self.prev_span),
+        })
+    }
 
-        self.writer.write(VmCommand::Label(&label2));
+    fn handle_do(&mut self) -> ParseResult<Statement> {
+        self.context.push("do statement");
+        self.open_tag("doStatement");
+        let start = self.curr_span;
+        self.consume(Do)?;
+        let name = self.consume_ident()?;
+        let call = self.handle_subroutine_call(name)?;
+        self.consume(';')?;
+        self.close_tag("doStatement");
+        self.context.pop();
+        Ok(Statement::Do {
+            call,
+            span: start.to(self.prev_span),
+        })
     }
 
-    fn handle_do(&mut self) {
-        self.consume(Do);
-        if let Token::Identifier(name) = self.consume(TokenType::Name) {
-            if let Some(Token::Symbol(c @ ('.' | '('))) = self.curr_token {
-                self.handle_subroutine_call(name, c);
-            }
-        }
-        self.consume(';');
+    fn handle_return(&mut self) -> ParseResult<Statement> {
+        self.context.push("return statement");
+        self.open_tag("returnStatement");
+        let start = self.curr_span;
+        self.consume(Return)?;
+        let value = if !self.curr_token_is(';') {
+            Some(self.handle_expression()?)
+        } else {
+            None
+        };
+        self.consume(';')?;
+        self.close_tag("returnStatement");
+        self.context.pop();
+        Ok(Statement::Return {
+            value,
+            span: start.to(self.prev_span),
+        })
+    }
 
-        // All "do" statements in Jack are "void" function calls
-        // which require discarding the return value that the VM implementation requires
-        self.writer.write(VmCommand::Pop(Mem::Temp, 0));
+    // Called with the first name already consumed, which is either
+    // the subroutine itself or the class/variable it is called on
+    fn handle_subroutine_call(&mut self, name: Ident) -> ParseResult<SubroutineCall> {
+        self.context.push("subroutine call");
+        let start = name.span;
+        let (receiver, name) = if self.curr_token_is('.') {
+            self.consume('.')?;
+            (Some(name), self.consume_ident()?)
+        } else {
+            (None, name)
+        };
+        self.consume('(')?;
+        let args = self.handle_expression_list()?;
+        self.consume(')')?;
+        self.context.pop();
+        Ok(SubroutineCall {
+            receiver,
+            name,
+            args,
+            span: start.to(self.prev_span),
+        })
     }
 
-    fn handle_return(&mut self) {
-        self.consume(Return);
-        if !self.curr_token_is(';') {
-            self.handle_expression();
+    fn handle_term(&mut self) -> ParseResult<Term> {
+        self.open_tag("term");
+        let start = self.curr_span;
+        // Unary operators apply to the term that follows them
+        let term = if self.curr_token_is(TokenType::UnaryOp) {
+            let op = match self.consume(TokenType::UnaryOp)? {
+                Token::Symbol('~') => UnaryOp::Not,
+                _ => UnaryOp::Neg,
+            };
+            let term = self.handle_term()?;
+            Term::Unary(op, Box::new(term), start.to(self.prev_span))
+        } else if self.curr_token_is('(') {
+            self.consume('(')?;
+            let expr = self.handle_expression()?;
+            self.consume(')')?;
+            Term::Paren(Box::new(expr), start.to(self.prev_span))
+        } else if self.curr_token_is(TokenType::Constant) {
+            Term::Constant(self.consume(TokenType::Constant)?, start)
         } else {
-            // The VM requires that a value is returned even if the type is void
-            self.writer.write(VmCommand::Push(Mem::Constant, 0));
-        }
-        self.writer.write(VmCommand::Return);
-        self.consume(';');
-    }
-
-    fn handle_subroutine_call(&mut self, name: String, next: char) {
-        // Easy way to add an extra argument if we determine the subroutine is a method and requires 'this'
-        let mut method = false;
-        let func_label: String;
-
-        self.consume(next);
-        if next == '.' {
-            let token = self.consume(Name);
-            self.consume('(');
-
-            // If the name is in the table we get its class for the label and push it so the method can be called
-            // Otherwise, it's simply a class function on its own
-            match (self.symbol_table.get(&name), token) {
-                (Some(entry), Token::Identifier(f)) => {
-                    self.writer.write(VmCommand::Push(
-                        entry.get_kind().to_mem_seg(),
-                        entry.get_id(),
-                    ));
-                    func_label = format!("{}.{}", entry.get_type(), f);
-                    method = true;
+            let name = self.consume_ident()?;
+            // Check whether we are evaluating as a subroutine call, an array entry or a value
+            match &self.curr_token {
+                Some(Token::Symbol('.' | '(')) => Term::Call(self.handle_subroutine_call(name)?),
+                Some(Token::Symbol('[')) => {
+                    self.consume('[')?;
+                    let index = self.handle_expression()?;
+                    self.consume(']')?;
+                    Term::Index(name, Box::new(index), start.to(self.prev_span))
                 }
-                (None, Token::Identifier(f)) => func_label = format!("{}.{}", name, f),
-                _ => func_label = String::from("error"),
+                _ => Term::Var(name),
             }
+        };
+        self.close_tag("term");
+        Ok(term)
+    }
+
+    fn handle_expression(&mut self) -> ParseResult<Expr> {
+        self.context.push("expression");
+        self.open_tag("expression");
+        let expr = if self.precedence {
+            self.handle_binary_op(0)?
         } else {
-            // Any calls without a '.' will be called from within this class
-            // so we can simply use the class name
-            self.writer.write(VmCommand::Push(Mem::Pointer, 0));
-            method = true;
-            func_label = format!("{}.{}", self.class_name, name);
-        }
+            let mut expr = Expr::Term(self.handle_term()?);
+            // Jack has no operator precedence, so `a + b * c` is `(a + b) * c`
+            // and each operator applies to everything to its left
+            let mut prev: Option<BinaryOp> = None;
+            let mut warned = false;
+            while let Some(op) = self.curr_binary_op() {
+                // Let the user know once per expression if precedence would have changed the result
+                if let Some(p) = prev.filter(|&p| !warned && precedence_of(op) > precedence_of(p)) {
+                    let mismatch = CompilationError::PrecedenceMismatch {
+                        first: p.symbol(),
+                        then: op.symbol(),
+                    };
+                    self.warn_at(mismatch, self.curr_span);
+                    warned = true;
+                }
+                prev = Some(op);
+                self.consume(TokenType::BinaryOp)?;
+                let rhs = Expr::Term(self.handle_term()?);
+                expr = Expr::Binary {
+                    op,
+                    span: expr.span().to(rhs.span()),
+                    lhs: Box::new(expr),
+                    rhs: Box::new(rhs),
+                };
+            }
+            expr
+        };
+        self.close_tag("expression");
+        self.context.pop();
+        Ok(expr)
+    }
 
-        let args = self.handle_expression_list();
-        self.consume(')');
+    // Precedence climbing, parses terms joined by operators that bind at least as tightly as `min`
+    // Recursing with a higher minimum for the right hand side keeps operators left associative
+    fn handle_binary_op(&mut self, min: u8) -> ParseResult<Expr> {
+        let mut expr = Expr::Term(self.handle_term()?);
+        while let Some(op) = self.curr_binary_op().filter(|&op| precedence_of(op) >= min) {
+            self.consume(TokenType::BinaryOp)?;
+            let rhs = self.handle_binary_op(precedence_of(op) + 1)?;
+            expr = Expr::Binary {
+                op,
+                span: expr.span().to(rhs.span()),
+                lhs: Box::new(expr),
+                rhs: Box::new(rhs),
+            };
+        }
+        Ok(expr)
+    }
 
-        self.writer
-            .write(VmCommand::Call(&func_label, args + method as i16));
+    fn curr_binary_op(&self) -> Option<BinaryOp> {
+        match self.curr_token {
+            Some(Token::Symbol(c)) => BinaryOp::from_symbol(c),
+            _ => None,
+        }
     }
 
-    fn handle_term(&mut self) {
-        // Check for unary operators
-        let op = if self.curr_token_is(TokenType::UnaryOp) {
-            match self.consume(TokenType::UnaryOp) {
-                Token::Symbol('-') => Some(VmCommand::Neg),
-                Token::Symbol('~') => Some(VmCommand::Not),
-                _ => None,
-            }
-        } else {
-            None
-        };
-        if self.curr_token_is('(') {
-            self.consume('(');
-            self.handle_expression();
-            self.consume(')');
-        } else if self.curr_token_is(TokenType::Constant) {
-            let token = self.consume(Constant);
-            self.writer.write_constant(token);
-        } else if let Token::Identifier(name) = self.consume(TokenType::Name) {
-            // Check whether we are evaluating as a subroutine call or as a value
-            match (self.symbol_table.get(&name), &self.curr_token) {
-                // Subroutine
-                (_, Some(Token::Symbol(c @ ('.' | '(')))) => self.handle_subroutine_call(name, *c),
-                // Value
-                (Some(entry), _) => {
-                    let (kind, id) = (entry.get_kind().to_mem_seg(), entry.get_id());
-                    if self.curr_token_is('[') {
-                        self.consume('[');
-                        self.handle_expression();
-                        self.consume(']');
-                        self.writer.write(VmCommand::Push(kind, id));
-                        self.writer.write(VmCommand::Add);
-                        self.writer.write(VmCommand::Pop(Mem::Pointer, 1));
-                        self.writer.write(VmCommand::Push(Mem::That, 0));
-                    } else {
-                        self.writer.write(VmCommand::Push(kind, id));
-                    }
+    // Arguments to a subroutine call, up to but not including the closing ')'
+    fn handle_expression_list(&mut self) -> ParseResult<Vec<Expr>> {
+        self.context.push("expression list");
+        self.open_tag("expressionList");
+        let mut exprs = vec![];
+        if !self.curr_token_is(')') {
+            loop {
+                exprs.push(self.handle_expression()?);
+                if !self.curr_token_is(',') {
+                    break;
                 }
-                (None, _) => self.throw_error(CompilationError::UndeclaredIdentifier),
-            }
-        }
-
-        // Use the unary operator if it exists
-        if let Some(o) = op {
-            self.writer.write(o);
-        }
-    }
-
-    // TODO: maybe add a label for operator priority to get a feel for it
-    // could return a tuple (Option<Term>, Option<Term>, Option<Term>)
-    // with a vector of said tuples that gets appended recursively
-    // until the top-level expression is complete
-    // first things first though
-    fn handle_expression(&mut self) {
-        self.handle_term();
-        if self.curr_token_is(TokenType::BinaryOp) {
-            let op = self.consume(TokenType::BinaryOp);
-            self.handle_term();
-            let op_cmd = match op {
-                Token::Symbol('+') => VmCommand::Add,
-                Token::Symbol('-') => VmCommand::Sub,
-                Token::Symbol('&') => VmCommand::And,
-                Token::Symbol('|') => VmCommand::Or,
-                Token::Symbol('=') => VmCommand::Compare(Eq),
-                Token::Symbol('>') => VmCommand::Compare(GT),
-                Token::Symbol('<') => VmCommand::Compare(LT),
-                Token::Symbol('*') => VmCommand::Call("Math.multiply", 2),
-                Token::Symbol('/') => VmCommand::Call("Math.divide", 2),
-                Token::Symbol('%') => VmCommand::Call("Math.modulo", 2),
-                _ => VmCommand::Label("not a binary op"),
-            };
-            self.writer.write(op_cmd);
+                self.consume(',')?;
+            }
         }
+        self.close_tag("expressionList");
+        self.context.pop();
+        Ok(exprs)
+    }
+}
+
+fn precedence_of(op: BinaryOp) -> u8 {
+    binary_precedence(op.symbol()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Compiles a single class in memory and returns the generated VM code
+    fn compile_to_vm(source: &str) -> String {
+        CompilationEngine::new()
+            .compile_str(source)
+            .unwrap()
+            .to_string()
+    }
+
+    // Parses a single class and returns its parse tree as XML
+    fn parse_tree_xml(source: &str) -> String {
+        let mut engine = CompilationEngine::new();
+        engine.xml = Some(XMLWriter::new(vec![]));
+        assert!(engine.parse(source.to_string()).is_ok());
+        String::from_utf8(engine.xml.take().unwrap().into_inner()).unwrap()
     }
 
-    // Evaluates the expressions and returns the total number of arguments for the function caller
-    fn handle_expression_list(&mut self) -> i16 {
-        let mut count: i16 = 0;
-        while !self.curr_token_is(')') {
-            self.handle_expression();
-            count += 1;
-            if self.curr_token_is(',') {
-                self.consume(',');
+    // Compiles a class that is expected to fail and returns its errors, leaving out any warnings
+    fn compile_errors(source: &str) -> Vec<Diagnostic> {
+        let mut errors = CompilationEngine::new().compile_str(source).unwrap_err();
+        errors.retain(|d| d.level == Level::Error);
+        errors
+    }
+
+    // Wraps a list of statements in a function with locals a, b, c and d
+    fn compile_statements(name: &str, statements: &str) -> Vec<String> {
+        let source = format!(
+            "class {name} {{ function int f() {{ var int a, b, c, d; {statements} return a; }} }}"
+        );
+        compile_to_vm(&source)
+            .lines()
+            .skip(1)
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_operator_chain_is_left_to_right() {
+        let vm = compile_statements("Chain", "let a = b + c * d - 2;");
+        let expected = [
+            "push local 1",
+            "push local 2",
+            "add",
+            "push local 3",
+            "call Math.multiply 2",
+            "push constant 2",
+            "sub",
+            "pop local 0",
+        ];
+        assert_eq!(&vm[..expected.len()], &expected);
+    }
+
+    #[test]
+    fn test_operator_chain_with_calls_and_comparisons() {
+        let vm = compile_statements(
+            "Mixed",
+            "let a = b / 2 * Math.max(c, d) < (a - 1) & ~(b = c);",
+        );
+        let expected = [
+            "push local 1",
+            "push constant 2",
+            "call Math.divide 2",
+            "push local 2",
+            "push local 3",
+            "call Math.max 2",
+            "call Math.multiply 2",
+            "push local 0",
+            "push constant 1",
+            "sub",
+            "lt",
+            "push local 1",
+            "push local 2",
+            "eq",
+            "not",
+            "and",
+            "pop local 0",
+        ];
+        assert_eq!(&vm[..expected.len()], &expected);
+    }
+
+    #[test]
+    fn test_operator_precedence_mode() {
+        let source = "class Prec { function int f(int a, int b, int c, int d) { \
+                      return a + b * c < d - a / b & a | b; } }";
+        let vm = CompilationEngine::new()
+            .with_precedence(true)
+            .compile_str(source)
+            .unwrap()
+            .to_string();
+        let expected = [
+            "push argument 0",
+            "push argument 1",
+            "push argument 2",
+            "call Math.multiply 2",
+            "add",
+            "push argument 3",
+            "push argument 0",
+            "push argument 1",
+            "call Math.divide 2",
+            "sub",
+            "lt",
+            "push argument 0",
+            "and",
+            "push argument 1",
+            "or",
+            "return",
+        ];
+        assert_eq!(vm.lines().skip(1).collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_precedence_mismatch_warning() {
+        let source = "class Warn { function int f(int a) { return (a * 2 + 1) + a * a + a; } }";
+        let warnings = CompilationEngine::new()
+            .compile_str(source)
+            .unwrap()
+            .warnings;
+        // Only the outer expression is affected, and it is only reported once
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].level, Level::Warning);
+        assert_eq!(warnings[0].span.col, 61);
+    }
+
+    #[test]
+    fn test_parse_tree_xml() {
+        let source = "class Main { function void main() { while (~x) {} return; } }";
+        let xml = parse_tree_xml(source);
+        let expected = "\
+<class>
+  <keyword> class </keyword>
+  <identifier> Main </identifier>
+  <symbol> { </symbol>
+  <subroutineDec>
+    <keyword> function </keyword>
+    <keyword> void </keyword>
+    <identifier> main </identifier>
+    <symbol> ( </symbol>
+    <parameterList>
+    </parameterList>
+    <symbol> ) </symbol>
+    <subroutineBody>
+      <symbol> { </symbol>
+      <statements>
+        <whileStatement>
+          <keyword> while </keyword>
+          <symbol> ( </symbol>
+          <expression>
+            <term>
+              <symbol> ~ </symbol>
+              <term>
+                <identifier> x </identifier>
+              </term>
+            </term>
+          </expression>
+          <symbol> ) </symbol>
+          <symbol> { </symbol>
+          <statements>
+          </statements>
+          <symbol> } </symbol>
+        </whileStatement>
+        <returnStatement>
+          <keyword> return </keyword>
+          <symbol> ; </symbol>
+        </returnStatement>
+      </statements>
+      <symbol> } </symbol>
+    </subroutineBody>
+  </subroutineDec>
+  <symbol> } </symbol>
+</class>
+";
+        assert_eq!(xml, expected);
+    }
+
+    #[test]
+    fn test_tokens_xml() {
+        let dir =
+            std::env::temp_dir().join(format!("hack_jack_test_tokens_xml_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("Main.jack");
+        std::fs::write(
+            &main,
+            "class Main {\n  // a < b\n  function void main() {\n    \
+             if ((1 < 2) & (3 > 2)) { do Output.printString(\"ok\"); }\n    return;\n  }\n}\n",
+        )
+        .unwrap();
+        let mut engine = CompilationEngine::new().with_emit(vec![Emit::TokensXml]);
+        engine.compile(main).unwrap();
+        let xml = std::fs::read_to_string(dir.join("MainT.xml")).unwrap();
+        let expected = "\
+<tokens>
+<keyword> class </keyword>
+<identifier> Main </identifier>
+<symbol> { </symbol>
+<keyword> function </keyword>
+<keyword> void </keyword>
+<identifier> main </identifier>
+<symbol> ( </symbol>
+<symbol> ) </symbol>
+<symbol> { </symbol>
+<keyword> if </keyword>
+<symbol> ( </symbol>
+<symbol> ( </symbol>
+<integerConstant> 1 </integerConstant>
+<symbol> &lt; </symbol>
+<integerConstant> 2 </integerConstant>
+<symbol> ) </symbol>
+<symbol> &amp; </symbol>
+<symbol> ( </symbol>
+<integerConstant> 3 </integerConstant>
+<symbol> &gt; </symbol>
+<integerConstant> 2 </integerConstant>
+<symbol> ) </symbol>
+<symbol> ) </symbol>
+<symbol> { </symbol>
+<keyword> do </keyword>
+<identifier> Output </identifier>
+<symbol> . </symbol>
+<identifier> printString </identifier>
+<symbol> ( </symbol>
+<stringConstant> ok </stringConstant>
+<symbol> ) </symbol>
+<symbol> ; </symbol>
+<symbol> } </symbol>
+<keyword> return </keyword>
+<symbol> ; </symbol>
+<symbol> } </symbol>
+<symbol> } </symbol>
+</tokens>
+";
+        assert_eq!(xml, expected);
+        // A quote only ever starts a string in Jack, but it's escaped like the others
+        assert_eq!(Token::Symbol('"').to_string(), "<symbol> &quot; </symbol>");
+    }
+
+    #[test]
+    fn test_no_parse_tree_for_broken_class() {
+        let dir = std::env::temp_dir().join(format!(
+            "hack_jack_test_no_parse_tree_for_broken_class_{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("Main.jack");
+        let mut engine = CompilationEngine::new().with_emit(vec![Emit::Xml]);
+        std::fs::write(&main, "class Main { function void main() { return; } }").unwrap();
+        engine.compile(main.clone()).unwrap();
+        assert!(dir.join("Main.xml").exists());
+
+        std::fs::remove_file(dir.join("Main.xml")).unwrap();
+        std::fs::write(
+            &main,
+            "class Main { function void main() { let = 1; return; } }",
+        )
+        .unwrap();
+        let mut engine = CompilationEngine::new().with_emit(vec![Emit::Xml]);
+        assert!(matches!(engine.compile(main), Err(Error::Compilation(_))));
+        assert!(!dir.join("Main.xml").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_reports_every_syntax_error_once() {
+        let source = "class Broken {
+            field int a
+            function void main() {
+                var int x;
+                let x = 1 x
+                do Output.printInt(x x);
+                if (x { let x = 1; }
+                while (x) { let x = ; }
+                return;
             }
+            method void f(int a, ) { return; }
+        }";
+        let lines: Vec<usize> = compile_errors(source).iter().map(|d| d.span.line).collect();
+        assert_eq!(lines, [3, 5, 6, 7, 8, 11]);
+    }
+
+    #[test]
+    fn test_terminates_on_truncated_input() {
+        let source = "class T { field int a; method int f(int b, char c) { var Array d; \
+                      let d[b] = f(a, -b); if (a) { do T.g(); } else { while (b) {} } return a; } }";
+        // Every prefix of the class is broken somewhere, but it should always be reported and never hang
+        for end in 0..source.len() {
+            assert!(!compile_errors(&source[..end]).is_empty());
         }
-        count
+    }
+
+    #[test]
+    fn test_duplicate_identifier_is_reported() {
+        let source = "class Dup { field int a, a; function void f(int b) { var int b; return; } }";
+        let errors = compile_errors(source);
+        assert_eq!(errors.len(), 2);
+        assert!(errors
+            .iter()
+            .all(|e| matches!(e.error, CompilationError::DuplicateIdentifier(_))));
+        assert_eq!(errors[0].span.col, 26);
+    }
+
+    #[test]
+    fn test_missing_source_is_io_error() {
+        let mut engine = CompilationEngine::new();
+        let file = std::env::temp_dir()
+            .join(format!(
+                "hack_jack_test_missing_source_is_io_error_{}",
+                std::process::id()
+            ))
+            .join("Missing.jack");
+        assert!(matches!(engine.compile(file), Err(Error::Io { .. })));
+    }
+
+    #[test]
+    fn test_compile_to_hack() {
+        let dir = std::env::temp_dir().join(format!(
+            "hack_jack_test_compile_to_hack_{}",
+            std::process::id()
+        ));
+        let out = dir.join("out");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&out).unwrap();
+        let main = dir.join("Main.jack");
+        std::fs::write(
+            &main,
+            "class Main { function void main() { do Sys.halt(); return; } }",
+        )
+        .unwrap();
+
+        // Without the OS there's nothing for the call to jump to
+        let mut engine = CompilationEngine::new().with_emit(vec![Emit::Hack]);
+        engine.compile(main.clone()).unwrap();
+        let error = engine.write_program(&dir.join("Prog")).unwrap_err();
+        assert!(matches!(&error, Error::Link { undefined, .. } if undefined == &["Sys.halt"]));
+        let sys = dir.join("Sys.vm");
+        std::fs::write(&sys, "function Sys.halt 0\nlabel LOOP\ngoto LOOP").unwrap();
+
+        // Only what was asked for is written, nothing in between
+        let mut engine = CompilationEngine::new()
+            .with_emit(vec![Emit::Hack])
+            .with_output(Output::Dir(out.clone()));
+        engine.compile(main).unwrap();
+        engine.translate_vm_files(&[sys]).unwrap();
+        engine.write_program(&dir.join("Prog")).unwrap();
+        let written: Vec<_> = std::fs::read_dir(&out)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(written, ["Prog.hack"]);
+        let hack = std::fs::read_to_string(out.join("Prog.hack")).unwrap();
+        assert!(hack.lines().all(|l| l.len() == 16));
     }
 }
@@ -0,0 +1,209 @@
+use crate::{
+    hack::{KBD, RAM_SIZE, ROM_SIZE, SCREEN},
+    screen,
+};
+
+// Runs .hack machine code the way the Hack CPU does, one instruction per cycle
+pub struct CpuEmulator {
+    rom: Vec<u16>,
+    ram: Vec<i16>,
+    a: i16,
+    d: i16,
+    pc: u16,
+    cycles: u64,
+}
+
+impl CpuEmulator {
+    // The rest of the ROM is zeroes, which is `@0`
+    pub fn new(program: &[u16]) -> Self {
+        let mut rom = program.to_vec();
+        rom.resize(ROM_SIZE, 0);
+        CpuEmulator {
+            rom,
+            ram: vec![0; RAM_SIZE],
+            a: 0,
+            d: 0,
+            pc: 0,
+            cycles: 0,
+        }
+    }
+
+    pub fn a(&self) -> i16 {
+        self.a
+    }
+
+    pub fn d(&self) -> i16 {
+        self.d
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn set_a(&mut self, a: i16) {
+        self.a = a;
+    }
+
+    pub fn set_d(&mut self, d: i16) {
+        self.d = d;
+    }
+
+    // Jumps to an instruction, addresses past the end of ROM wrap around as jumps do
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc % ROM_SIZE as u16;
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn ram(&self) -> &[i16] {
+        &self.ram
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [i16] {
+        &mut self.ram
+    }
+
+    pub fn screen(&self) -> &[i16] {
+        &self.ram[SCREEN..SCREEN + screen::WORDS]
+    }
+
+    // The key currently held down, 0 for none
+    pub fn set_key(&mut self, key: i16) {
+        self.ram[KBD] = key;
+    }
+
+    // Back to the first instruction, RAM is left as it is
+    pub fn reset(&mut self) {
+        self.pc = 0;
+    }
+
+    pub fn run(&mut self, cycles: u64) {
+        for _ in 0..cycles {
+            self.step();
+        }
+    }
+
+    pub fn step(&mut self) {
+        self.cycles += 1;
+        let instruction = self.rom[self.pc as usize];
+        if instruction & 0x8000 == 0 {
+            self.a = instruction as i16;
+            self.pc = (self.pc + 1) % ROM_SIZE as u16;
+            return;
+        }
+
+        let address = self.a as u16 as usize % RAM_SIZE;
+        let y = if instruction & 0x1000 != 0 {
+            self.ram[address]
+        } else {
+            self.a
+        };
+        let out = alu(self.d, y, instruction >> 6);
+        // Everything is worked out from the registers as they were before this instruction
+        let jump = instruction & 0b111;
+        let taken = (jump & 0b100 != 0 && out < 0)
+            || (jump & 0b010 != 0 && out == 0)
+            || (jump & 0b001 != 0 && out > 0);
+        let target = self.a as u16 % ROM_SIZE as u16;
+        if instruction & 0b001000 != 0 {
+            self.ram[address] = out;
+        }
+        if instruction & 0b010000 != 0 {
+            self.d = out;
+        }
+        if instruction & 0b100000 != 0 {
+            self.a = out;
+        }
+        self.pc = if taken {
+            target
+        } else {
+            (self.pc + 1) % ROM_SIZE as u16
+        };
+    }
+}
+
+// The six control bits from zx down to no, in the lowest bits of `control`
+fn alu(x: i16, y: i16, control: u16) -> i16 {
+    let bit = |n: u16| control & (1 << n) != 0;
+    let x = if bit(5) { 0 } else { x };
+    let x = if bit(4) { !x } else { x };
+    let y = if bit(3) { 0 } else { y };
+    let y = if bit(2) { !y } else { y };
+    let out = if bit(1) { x.wrapping_add(y) } else { x & y };
+    if bit(0) {
+        !out
+    } else {
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::assemble, CompilationEngine, VmTranslator};
+
+    #[test]
+    fn test_run_assembly() {
+        // Multiplies R0 by R1 into R2, then loops
+        let program = assemble(
+            "@R2\nM=0\n(LOOP)\n@R1\nD=M\n@END\nD;JLE\n@R0\nD=M\n@R2\nM=D+M\n@R1\nM=M-1\n\
+             @LOOP\n0;JMP\n(END)\n@END\n0;JMP",
+        )
+        .unwrap();
+        let mut cpu = CpuEmulator::new(&program);
+        cpu.ram_mut()[0] = 6;
+        cpu.ram_mut()[1] = 7;
+        cpu.run(200);
+        assert_eq!(cpu.ram()[2], 42);
+        assert!(matches!(cpu.pc(), 14 | 15));
+        assert_eq!(cpu.a(), 14);
+        assert_eq!(cpu.cycles(), 200);
+    }
+
+    // Compiled all the way down to machine code, with a Sys in place of the OS
+    #[test]
+    fn test_run_compiled_program() {
+        let sources = [
+            "class Sys { function void init() { do Main.main(); while (true) {} return; } }",
+            "class Main { function int fib(int n) { if (n < 2) { return n; } \
+             return Main.fib(n - 1) + Main.fib(n - 2); } \
+             function void main() { var Array mem; let mem = 8000; \
+             let mem[0] = Main.fib(12); let mem[1] = -32767 < 32767; let mem[2] = 30000 > -30000; \
+             let mem = 16384; let mem[32] = -1; return; } }",
+        ];
+        let mut engine = CompilationEngine::new();
+        sources.iter().for_each(|source| engine.index_str(source));
+        let mut translator = VmTranslator::new();
+        for source in sources {
+            let program = engine.compile_str(source).unwrap();
+            translator.translate(&program.class_name, &program.commands);
+        }
+        let mut cpu = CpuEmulator::new(&assemble(&translator.finish()).unwrap());
+        cpu.run(100_000);
+        assert_eq!(&cpu.ram()[8000..8003], [144, -1, -1]);
+        assert_eq!(
+            cpu.ram()[1],
+            261,
+            "back in Sys.init, called by the bootstrap"
+        );
+        assert!(screen::pixel(cpu.screen(), 15, 1) && !screen::pixel(cpu.screen(), 16, 1));
+
+        let mut pbm = vec![];
+        screen::write_pbm(cpu.screen(), &mut pbm).unwrap();
+        assert!(pbm.starts_with(b"P4\n512 256\n"));
+        assert_eq!(pbm.len(), 11 + 512 * 256 / 8);
+        assert_eq!(&pbm[11 + 64..11 + 67], [0xff, 0xff, 0]);
+
+        // Pixel (15, 1) is black and (16, 1) white, three bytes each
+        let mut ppm = vec![];
+        screen::write_ppm(cpu.screen(), &mut ppm).unwrap();
+        let header = b"P6\n512 256\n255\n";
+        assert!(ppm.starts_with(header));
+        assert_eq!(ppm.len(), header.len() + 512 * 256 * 3);
+        let at = |x: usize, y: usize| header.len() + (y * 512 + x) * 3;
+        assert_eq!(&ppm[at(15, 1)..at(15, 1) + 3], [0, 0, 0]);
+        assert_eq!(&ppm[at(16, 1)..at(16, 1) + 3], [255, 255, 255]);
+    }
+}
@@ -0,0 +1,139 @@
+use std::{
+    fmt::{Display, Write},
+    path::Path,
+};
+
+use crate::{compilation_engine::CompilationError, tokenizer::Span};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Level::Error => write!(f, "error"),
+            Level::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub level: Level,
+    pub error: CompilationError,
+    pub span: Span,
+    // The construct being parsed when the error was found, e.g. "let statement"
+    pub context: Option<&'static str>,
+}
+
+impl Diagnostic {
+    pub fn message(&self) -> String {
+        match (&self.error, self.context) {
+            (CompilationError::UnexpectedToken { expected, found }, Some(ctx)) => {
+                format!("expected {expected} in {ctx}, found {}", found.describe())
+            }
+            (CompilationError::UnexpectedEndofTokens { expected }, Some(ctx)) => {
+                format!("expected {expected} in {ctx}, found end of file")
+            }
+            (CompilationError::TypeMismatch { expected, found }, Some(ctx)) => {
+                format!("mismatched types in {ctx}: expected `{expected}`, found `{found}`")
+            }
+            (err, _) => format!("{err}"),
+        }
+    }
+}
+
+// Renders every diagnostic for a single file, each with the offending line and a caret underline:
+//
+// error[E0003]: expected ';' in let statement, found identifier `x`
+//   --> Main.jack:8:15
+//    |
+//  8 |         let y = 1 x
+//    |                   ^
+pub fn render(path: &Path, source: &str, diagnostics: &[Diagnostic]) -> String {
+    let mut out = String::new();
+    // Leave enough room in the gutter for the biggest line number
+    let gutter = diagnostics
+        .iter()
+        .map(|d| d.span.line.to_string().len())
+        .max()
+        .unwrap_or(1);
+    for d in diagnostics {
+        let Span {
+            start,
+            end,
+            line,
+            col,
+        } = d.span;
+        let text = source.lines().nth(line.saturating_sub(1)).unwrap_or("");
+        // Keep tabs so the caret lines up with the source line as printed
+        let pad: String = text
+            .chars()
+            .take(col.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = source
+            .get(start..end)
+            .map(|s| s.lines().next().unwrap_or("").chars().count())
+            .unwrap_or(0)
+            .max(1);
+
+        let _ = writeln!(out, "{}[{}]: {}", d.level, d.error.code(), d.message());
+        let _ = writeln!(out, "{:gutter$}--> {}:{line}:{col}", "", path.display());
+        let _ = writeln!(out, "{:gutter$} |", "");
+        let _ = writeln!(out, "{line:>gutter$} | {text}");
+        let _ = writeln!(out, "{:gutter$} | {pad}{}", "", "^".repeat(width));
+        out.push('\n');
+    }
+    let plural = |n: usize| if n == 1 { "" } else { "s" };
+    let errors = diagnostics
+        .iter()
+        .filter(|d| d.level == Level::Error)
+        .count();
+    let warnings = diagnostics.len() - errors;
+    let mut summary = vec![];
+    if errors > 0 {
+        summary.push(format!("{errors} error{}", plural(errors)));
+    }
+    if warnings > 0 {
+        summary.push(format!("{warnings} warning{}", plural(warnings)));
+    }
+    let _ = writeln!(out, "{}: {}", path.display(), summary.join(", "));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{token_type::Expected, tokens::Token};
+
+    #[test]
+    fn test_render_snippet() {
+        let source = "class Main {\n\tlet y = 1 x\n}";
+        let diagnostic = Diagnostic {
+            level: Level::Error,
+            error: CompilationError::UnexpectedToken {
+                expected: Expected::Symbol(';'),
+                found: Token::Identifier(String::from("x")),
+            },
+            span: Span {
+                start: 24,
+                end: 25,
+                line: 2,
+                col: 12,
+            },
+            context: Some("let statement"),
+        };
+        let out = render(Path::new("Main.jack"), source, &[diagnostic]);
+        let expected = "error[E0003]: expected ';' in let statement, found identifier `x`\n \
+                        --> Main.jack:2:12\n  \
+                        |\n\
+                        2 | \tlet y = 1 x\n  \
+                        | \t          ^\n\n\
+                        Main.jack: 1 error\n";
+        assert_eq!(out, expected);
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    assembler::LineErrors, diagnostics::Diagnostic, native_os, vm_interpreter::RuntimeError,
+};
+
+// Everything that can stop a compile, as opposed to the diagnostics reported within a class
+#[derive(Debug)]
+pub enum Error {
+    // Reading a source file or writing an output file failed
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    // The command line didn't make sense
+    Usage(String),
+    // The class had at least one error, every diagnostic for it is included
+    Compilation(Vec<Diagnostic>),
+    // A test script stopped the program it was running with an error
+    Runtime {
+        path: PathBuf,
+        source: RuntimeError,
+    },
+    // A line of a test script's output didn't match the compare file, numbered from 1
+    Comparison {
+        path: PathBuf,
+        line: usize,
+        expected: String,
+        found: String,
+    },
+    // The linked program calls functions that none of its classes define
+    Link {
+        path: PathBuf,
+        undefined: Vec<String>,
+    },
+    // Lines of VM code or assembly that couldn't be read, numbered from 1
+    Syntax {
+        path: PathBuf,
+        errors: LineErrors,
+    },
+}
+
+impl Error {
+    pub fn io(path: &Path, source: io::Error) -> Self {
+        Error::Io {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+
+    // Follows the BSD sysexits convention so scripts can tell failures apart
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Compilation(_) | Error::Comparison { .. } | Error::Link { .. } => 1,
+            Error::Usage(_) => 64,
+            Error::Syntax { .. } => 65,
+            Error::Runtime { .. } => 70,
+            Error::Io { .. } => 74,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io { path, source } => write!(f, "{}: {source}", path.display()),
+            Error::Usage(msg) => write!(f, "{msg}"),
+            Error::Runtime { path, source } => write!(f, "{}: {source}", path.display()),
+            Error::Comparison {
+                path,
+                line,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{}: comparison failure at line {line}\nexpected: {expected}\n   found: {found}",
+                path.display()
+            ),
+            Error::Link { path, undefined } => {
+                write!(
+                    f,
+                    "{}: calls functions that are never defined: {}",
+                    path.display(),
+                    undefined.join(", ")
+                )?;
+                if undefined
+                    .iter()
+                    .any(|name| native_os::lookup(name).is_some())
+                {
+                    write!(
+                        f,
+                        "\nthe OS has to be linked in too, put its .vm files next to the program"
+                    )?;
+                }
+                Ok(())
+            }
+            // One line for each
+            Error::Syntax { path, errors } => {
+                let lines: Vec<_> = errors
+                    .iter()
+                    .map(|(line, message)| format!("{}:{line}: {message}", path.display()))
+                    .collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            Error::Compilation(diagnostics) => {
+                write!(
+                    f,
+                    "compilation failed with {} diagnostics",
+                    diagnostics.len()
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Runtime { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
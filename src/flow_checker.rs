@@ -0,0 +1,122 @@
+use crate::{
+    ast::*,
+    compilation_engine::CompilationError,
+    diagnostics::{Diagnostic, Level},
+    tokenizer::Span,
+    tokens::{Keyword, Token},
+};
+
+// Checks that every subroutine returns the way its declaration says it does
+pub fn check(class: &Class) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    let mut report = |error, span| {
+        diagnostics.push(Diagnostic {
+            level: Level::Error,
+            error,
+            span,
+            context: None,
+        })
+    };
+    for subroutine in &class.subroutines {
+        let name = format!("{}.{}", class.name.name, subroutine.name.name);
+        for (value, span) in returns(&subroutine.statements) {
+            match (subroutine.kind, &subroutine.return_type, value) {
+                (SubroutineKind::Constructor, _, Some(value)) if is_this(value) => {}
+                (SubroutineKind::Constructor, _, _) => {
+                    report(CompilationError::ConstructorReturn, span)
+                }
+                (_, None, Some(value)) => report(
+                    CompilationError::ReturnValueInVoid(name.clone()),
+                    value.span(),
+                ),
+                (_, Some(t), None) => {
+                    report(CompilationError::MissingReturnValue(t.to_string()), span)
+                }
+                _ => {}
+            }
+        }
+        // The VM would carry on into whatever function comes next
+        if !always_returns(&subroutine.statements) {
+            report(
+                CompilationError::MissingReturn {
+                    name,
+                    return_type: subroutine.return_type.as_ref().map(|t| t.to_string()),
+                },
+                subroutine.name.span,
+            );
+        }
+    }
+    diagnostics
+}
+
+fn is_this(value: &Expr) -> bool {
+    matches!(
+        value,
+        Expr::Term(Term::Constant(Token::Keyword(Keyword::This), _))
+    )
+}
+
+// Every return statement, however deeply nested
+fn returns(statements: &[Statement]) -> Vec<(Option<&Expr>, Span)> {
+    let mut found = vec![];
+    for statement in statements {
+        match statement {
+            Statement::Return { value, span } => found.push((value.as_ref(), *span)),
+            Statement::If {
+                then, otherwise, ..
+            } => {
+                found.extend(returns(then));
+                found.extend(returns(otherwise.as_deref().unwrap_or_default()));
+            }
+            Statement::While { body, .. } => found.extend(returns(body)),
+            Statement::Let { .. } | Statement::Do { .. } => {}
+        }
+    }
+    found
+}
+
+// A loop's condition might be false from the start, so only an if with both branches counts
+fn always_returns(statements: &[Statement]) -> bool {
+    statements.iter().any(|statement| match statement {
+        Statement::Return { .. } => true,
+        Statement::If {
+            then,
+            otherwise: Some(otherwise),
+            ..
+        } => always_returns(then) && always_returns(otherwise),
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{CompilationEngine, Strictness};
+
+    #[test]
+    fn test_return_paths() {
+        let source = "class Main { field int x; \
+                      constructor Main new() { if (x) { return this; } return 0; } \
+                      function int a(int b) { if (b) { return 1; } else { return 2; } } \
+                      function int c(int b) { if (b) { return 1; } while (b) { return 2; } } \
+                      function void d() { return 3; } \
+                      method int e() { do Output.println(); return; } \
+                      method int f() { return Main.d(); } }";
+        let errors: Vec<_> = CompilationEngine::new()
+            .with_type_check(Strictness::Off)
+            .compile_str(source)
+            .unwrap_err()
+            .into_iter()
+            .map(|d| d.error.to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "constructors have to `return this;`",
+                "`Main.c` has to return a value of type `int` on every path",
+                "`Main.d` is void but returns a value",
+                "this return needs a value of type `int`",
+                "`Main.d` returns void, so there's no value to use",
+            ]
+        );
+    }
+}
@@ -0,0 +1,10 @@
+// The Hack computer's memory map, shared by the assembler, the CPU emulator
+// and the VM interpreter
+
+// Instruction memory, also the largest value an A instruction can load
+pub const ROM_SIZE: usize = 32768;
+pub const RAM_SIZE: usize = 32768;
+// The memory mapped screen, see the screen module for its layout
+pub const SCREEN: usize = 16384;
+// The key currently held down, 0 for none
+pub const KBD: usize = 24576;
@@ -0,0 +1,121 @@
+#[macro_use]
+extern crate lazy_static;
+
+pub mod assembler;
+pub mod ast;
+pub mod code_generator;
+pub mod compilation_engine;
+pub mod cpu_emulator;
+pub mod diagnostics;
+pub mod error;
+pub mod flow_checker;
+pub mod hack;
+mod native_os;
+pub mod os_api;
+pub mod program_index;
+pub mod screen;
+pub mod symbol_table;
+pub mod test_script;
+pub mod token_type;
+pub mod tokenizer;
+pub mod tokens;
+pub mod type_checker;
+pub mod usage_checker;
+pub mod vm_interpreter;
+pub mod vm_translator;
+pub mod vm_writer;
+pub mod xml_writer;
+
+pub use code_generator::{LabelStyle, VmProgram};
+pub use compilation_engine::{CompilationEngine, CompilationError, Emit, Output};
+pub use cpu_emulator::CpuEmulator;
+pub use diagnostics::{Diagnostic, Level};
+pub use error::Error;
+pub use program_index::ProgramIndex;
+pub use symbol_table::SymbolTable;
+pub use tokenizer::{Span, Tokenizer};
+pub use tokens::Token;
+pub use type_checker::Strictness;
+pub use vm_interpreter::{RuntimeError, VmInterpreter};
+pub use vm_translator::VmTranslator;
+pub use vm_writer::VmCommand;
+
+// Every problem found in a class, in source order
+pub type Diagnostics = Vec<Diagnostic>;
+
+// Compiles the source of a single Jack class in memory with the default options,
+// it can only call itself and the OS, use compile_program for anything bigger
+pub fn compile_str(source: &str) -> Result<VmProgram, Diagnostics> {
+    CompilationEngine::new().compile_str(source)
+}
+
+// Compiles the sources of all the classes of a program in memory, every class is indexed
+// first so calls between them are checked. The diagnostics of every failed class are returned
+pub fn compile_program(sources: &[&str]) -> Result<Vec<VmProgram>, Diagnostics> {
+    let mut engine = CompilationEngine::new();
+    for source in sources {
+        engine.index_str(source);
+    }
+    let mut programs = vec![];
+    let mut errors = vec![];
+    for source in sources {
+        match engine.compile_str(source) {
+            Ok(program) => programs.push(program),
+            Err(diagnostics) => errors.extend(diagnostics),
+        }
+    }
+    if errors.is_empty() {
+        Ok(programs)
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compile_str() {
+        let source = "class Main { function int f(int a) { var int b; let b = a + 1; return b; } }";
+        let program = compile_str(source).unwrap();
+        assert_eq!(program.class_name, "Main");
+        assert!(program.warnings.is_empty());
+        assert_eq!(
+            program.to_string(),
+            "function Main.f 1\npush argument 0\npush constant 1\nadd\npop local 0\n\
+             push local 0\nreturn\n"
+        );
+
+        let errors =
+            compile_str("class Main { function void f() { let x = 1; return; } }").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            errors[0].error,
+            CompilationError::UndeclaredIdentifier(_)
+        ));
+    }
+
+    #[test]
+    fn test_compile_program() {
+        let main = "class Main { function void main() { var Point p; \
+                    let p = Point.new(1); do Output.printInt(p.getX()); return; } }";
+        let point = "class Point { field int x; \
+                     constructor Point new(int ax) { let x = ax; return this; } \
+                     method int getX() { return x; } }";
+        assert!(compile_str(main).is_err());
+
+        let programs = compile_program(&[main, point]).unwrap();
+        let names: Vec<_> = programs.iter().map(|p| p.class_name.as_str()).collect();
+        assert_eq!(names, ["Main", "Point"]);
+        assert!(programs[0].to_string().contains("call Point.new 1\n"));
+        assert!(programs[0].to_string().contains("call Point.getX 1\n"));
+
+        let errors = compile_program(&[main, point.replace("getX", "getY").as_str()]).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            errors[0].error,
+            CompilationError::UnknownSubroutine { .. }
+        ));
+    }
+}
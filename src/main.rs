@@ -1,33 +1,200 @@
-use compilation_engine::CompilationEngine;
-use std::path::{Path, PathBuf};
-
-#[macro_use]
-extern crate lazy_static;
-
-mod compilation_engine;
-mod symbol_table;
-mod token_type;
-mod tokenizer;
-mod tokens;
-mod vm_writer;
-
-fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    let mut files: Vec<PathBuf> = vec![];
-    let file_path = Path::new(&args[1]);
-    let mut parser = CompilationEngine::new();
-    if file_path.is_dir() {
-        for entry in file_path.read_dir().unwrap() {
-            if let Some(x) = entry.as_ref().unwrap().path().extension() {
-                if x.to_str().unwrap() == "jack" {
-                    files.push(entry.as_ref().unwrap().path())
+use hack_jack_compiler::{
+    diagnostics, test_script, CompilationEngine, Emit, Error, LabelStyle, Output, Strictness,
+};
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+const USAGE: &str =
+    "usage: hack_jack_compiler [--precedence] [--types off|lenient|strict] [--labels scoped|reference] [--os-api <dir>] [--emit vm,asm,hack,xml,tokens-xml] [-o <dir | ->] <file.jack | file.asm | file.tst | directory>";
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(code) => code,
+        Err(e) => {
+            match e {
+                // Every bad line gets an error of its own
+                Error::Syntax { .. } => e
+                    .to_string()
+                    .lines()
+                    .for_each(|line| eprintln!("error: {line}")),
+                _ => eprintln!("error: {e}"),
+            }
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+// Compile errors are reported as each file is compiled, anything else stops the whole run
+fn run() -> Result<ExitCode, Error> {
+    let mut path = None;
+    let mut precedence = false;
+    let mut emit = vec![];
+    let mut output = Output::SourceDir;
+    let mut types = Strictness::Lenient;
+    let mut labels = LabelStyle::Scoped;
+    let mut os_api = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--precedence" => precedence = true,
+            // Any number of comma separated outputs, e.g. `--emit vm,tokens-xml`
+            "--emit" => {
+                for name in args.next().unwrap_or_default().split(',') {
+                    let e = Emit::from_name(name).ok_or_else(|| {
+                        Error::Usage(format!("unknown output `{name}` for --emit\n{USAGE}"))
+                    })?;
+                    emit.push(e);
                 }
             }
+            "--types" => {
+                let name = args.next().unwrap_or_default();
+                types = Strictness::from_name(&name).ok_or_else(|| {
+                    Error::Usage(format!("unknown strictness `{name}` for --types\n{USAGE}"))
+                })?;
+            }
+            "--labels" => {
+                let name = args.next().unwrap_or_default();
+                labels = LabelStyle::from_name(&name).ok_or_else(|| {
+                    Error::Usage(format!(
+                        "unknown label style `{name}` for --labels\n{USAGE}"
+                    ))
+                })?;
+            }
+            // Signatures for a custom OS build, in place of the built in ones
+            "--os-api" => {
+                let dir = args
+                    .next()
+                    .ok_or_else(|| Error::Usage(format!("--os-api needs a directory\n{USAGE}")))?;
+                os_api = Some(PathBuf::from(dir));
+            }
+            // `-` writes everything to stdout instead of separate files
+            "-o" => {
+                output = match args.next().as_deref() {
+                    Some("-") => Output::Stdout,
+                    Some(dir) => Output::Dir(PathBuf::from(dir)),
+                    None => {
+                        return Err(Error::Usage(format!("-o needs a directory or -\n{USAGE}")))
+                    }
+                }
+            }
+            _ if arg.starts_with('-') => {
+                return Err(Error::Usage(format!("unknown option `{arg}`\n{USAGE}")));
+            }
+            _ => path = Some(PathBuf::from(arg)),
         }
-    } else if let Some("jack") = file_path.extension().unwrap().to_str() {
-        files.push(file_path.to_path_buf())
     }
+    let file_path = path.ok_or_else(|| Error::Usage(String::from(USAGE)))?;
+    if emit.is_empty() {
+        emit.push(Emit::Vm);
+    }
+    if let Output::Dir(dir) = &output {
+        std::fs::create_dir_all(dir).map_err(|e| Error::io(dir, e))?;
+    }
+
+    // Assembly is taken straight to machine code, there's nothing to compile
+    if file_path.extension().is_some_and(|x| x == "asm") {
+        CompilationEngine::new()
+            .with_output(output)
+            .assemble_file(&file_path)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    // Test scripts run the program they load and compare its output
+    if file_path.extension().is_some_and(|x| x == "tst") {
+        test_script::run_script(&file_path)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let is_jack = |p: &Path| p.extension().is_some_and(|x| x == "jack");
+    // A single file still gets to call the other classes next to it
+    let (files, program, dir) = if file_path.is_dir() {
+        let files = jack_files(&file_path)?;
+        (files.clone(), files, file_path.clone())
+    } else if is_jack(&file_path) {
+        let dir = match file_path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        (vec![file_path.clone()], jack_files(dir)?, dir.to_path_buf())
+    } else {
+        return Err(Error::Usage(format!(
+            "{} is not a .jack, .asm or .tst file or a directory\n{USAGE}",
+            file_path.display()
+        )));
+    };
+
+    let mut parser = CompilationEngine::new()
+        .with_precedence(precedence)
+        .with_emit(emit)
+        .with_output(output)
+        .with_type_check(types)
+        .with_labels(labels);
+    if let Some(dir) = os_api {
+        parser.index_files(&jack_files(&dir)?)?;
+    }
+    parser.index_files(&program)?;
+    // A linked program needs the code of every class it can call, not just the one asked for
+    let files = if parser.links() {
+        program.clone()
+    } else {
+        files
+    };
+    let mut failed = false;
     for file in files {
-        parser.compile(file).expect("error");
+        let diagnostics = match parser.compile(file.clone()) {
+            Ok(warnings) => warnings.to_vec(),
+            Err(Error::Compilation(errors)) => {
+                failed = true;
+                errors
+            }
+            Err(e) => return Err(e),
+        };
+        if !diagnostics.is_empty() {
+            let source = std::fs::read_to_string(&file).map_err(|e| Error::io(&file, e))?;
+            eprint!("{}", diagnostics::render(&file, &source, &diagnostics));
+        }
+    }
+    // One program for everything, including any .vm files that don't come from
+    // the Jack sources, like a copy of the OS
+    if parser.links() && !failed {
+        let compiled: Vec<_> = program.iter().filter_map(|f| f.file_stem()).collect();
+        let vm_files: Vec<_> = files_with_extension(&dir, "vm")?
+            .into_iter()
+            .filter(|f| !f.file_stem().is_some_and(|s| compiled.contains(&s)))
+            .collect();
+        parser.translate_vm_files(&vm_files)?;
+        let program_path = if file_path.is_dir() {
+            let name = file_path
+                .canonicalize()
+                .map_err(|e| Error::io(&file_path, e))?;
+            file_path.join(name.file_name().unwrap_or_default())
+        } else {
+            file_path.with_extension("")
+        };
+        parser.write_program(&program_path)?;
+    }
+    Ok(if failed {
+        ExitCode::from(Error::Compilation(vec![]).exit_code())
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+fn jack_files(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    files_with_extension(dir, "jack")
+}
+
+fn files_with_extension(dir: &Path, extension: &str) -> Result<Vec<PathBuf>, Error> {
+    let mut files = vec![];
+    let entries = dir.read_dir().map_err(|e| Error::io(dir, e))?;
+    for entry in entries {
+        let path = entry.map_err(|e| Error::io(dir, e))?.path();
+        if path.extension().is_some_and(|x| x == extension) {
+            files.push(path)
+        }
     }
+    // Directory order isn't stable between platforms
+    files.sort();
+    Ok(files)
 }
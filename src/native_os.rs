@@ -0,0 +1,586 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Read, Write},
+};
+
+use crate::{
+    hack::{KBD, RAM_SIZE, SCREEN},
+    vm_interpreter::{RuntimeError, VmInterpreter},
+};
+
+const HEAP_START: i16 = 2048;
+const HEAP_END: i16 = SCREEN as i16;
+const SCREEN_WIDTH: i16 = 512;
+const SCREEN_HEIGHT: i16 = 256;
+// Codes of the Jack character set that aren't ASCII
+const NEW_LINE: i16 = 128;
+const BACKSPACE: i16 = 129;
+const DOUBLE_QUOTE: i16 = 34;
+// How long a line Keyboard.readLine can read
+const LINE_LENGTH: i16 = 80;
+
+// Takes the arguments in order and returns the value left on the stack, 0 for void
+pub(crate) type Native = fn(&mut VmInterpreter, &[i16]) -> Result<i16, RuntimeError>;
+
+// The state of the OS that isn't kept in RAM
+pub(crate) struct NativeOs {
+    pub(crate) output: Box<dyn Write>,
+    pub(crate) keyboard: Box<dyn Read>,
+    color: bool,
+    // Free blocks of the heap by where they start, and the size of every allocated one
+    free: BTreeMap<i16, i16>,
+    allocated: HashMap<i16, i16>,
+}
+
+impl NativeOs {
+    pub(crate) fn new() -> Self {
+        NativeOs {
+            output: Box::new(io::stdout()),
+            keyboard: Box::new(io::empty()),
+            color: true,
+            free: BTreeMap::from([(HEAP_START, HEAP_END - HEAP_START)]),
+            allocated: HashMap::new(),
+        }
+    }
+}
+
+// Every function of the Jack OS with the number of arguments it takes
+// Any of them loaded as VM code, e.g. a class of the user's own OS, is used instead
+pub(crate) fn lookup(function: &str) -> Option<(usize, Native)> {
+    let native: (usize, Native) = match function {
+        "Math.init" | "Output.init" | "Keyboard.init" => (0, |_, _| Ok(0)),
+        "Math.abs" => (1, |_, a| Ok(a[0].wrapping_abs())),
+        "Math.multiply" => (2, |_, a| Ok(a[0].wrapping_mul(a[1]))),
+        "Math.divide" => (2, divide),
+        "Math.min" => (2, |_, a| Ok(a[0].min(a[1]))),
+        "Math.max" => (2, |_, a| Ok(a[0].max(a[1]))),
+        "Math.sqrt" => (1, sqrt),
+        "Memory.init" => (0, memory_init),
+        "Memory.peek" => (1, |vm, a| read(vm, a[0], 0)),
+        "Memory.poke" => (2, |vm, a| write(vm, a[0], 0, a[1]).map(|_| 0)),
+        "Memory.alloc" => (1, alloc),
+        "Memory.deAlloc" => (1, de_alloc),
+        "Array.new" => (1, array_new),
+        "Array.dispose" | "String.dispose" => (1, |vm, a| vm.invoke("Memory.deAlloc", a)),
+        "String.new" => (1, string_new),
+        "String.length" => (1, |vm, a| read(vm, a[0], 1)),
+        "String.charAt" => (2, char_at),
+        "String.setCharAt" => (3, set_char_at),
+        "String.appendChar" => (2, append_char),
+        "String.eraseLastChar" => (1, erase_last_char),
+        "String.intValue" => (1, int_value),
+        "String.setInt" => (2, set_int),
+        "String.backSpace" => (0, |_, _| Ok(BACKSPACE)),
+        "String.doubleQuote" => (0, |_, _| Ok(DOUBLE_QUOTE)),
+        "String.newLine" => (0, |_, _| Ok(NEW_LINE)),
+        "Output.moveCursor" => (2, move_cursor),
+        "Output.printChar" => (1, print_char),
+        "Output.printString" => (1, print_string),
+        "Output.printInt" => (1, print_int),
+        "Output.println" => (0, |vm, _| vm.invoke("Output.printChar", &[NEW_LINE])),
+        "Output.backSpace" => (0, |vm, _| vm.invoke("Output.printChar", &[BACKSPACE])),
+        "Screen.init" => (0, |vm, _| set_color(vm, &[-1])),
+        "Screen.clearScreen" => (0, clear_screen),
+        "Screen.setColor" => (1, set_color),
+        "Screen.drawPixel" => (2, draw_pixel),
+        "Screen.drawLine" => (4, draw_line),
+        "Screen.drawRectangle" => (4, draw_rectangle),
+        "Screen.drawCircle" => (3, draw_circle),
+        "Keyboard.keyPressed" => (0, key_pressed),
+        "Keyboard.readChar" => (0, read_char),
+        "Keyboard.readLine" => (1, read_line),
+        "Keyboard.readInt" => (1, read_int),
+        "Sys.init" => (0, sys_init),
+        "Sys.halt" => (0, |vm, _| {
+            vm.halt();
+            Ok(0)
+        }),
+        "Sys.error" => (1, |vm, a| Err(os_error(vm, a[0]))),
+        "Sys.wait" => (1, |vm, a| {
+            if a[0] < 0 {
+                Err(os_error(vm, 1))
+            } else {
+                Ok(0)
+            }
+        }),
+        _ => return None,
+    };
+    Some(native)
+}
+
+// The error codes of the course OS, stopping the program the same way Sys.error does
+fn os_error(vm: &mut VmInterpreter, code: i16) -> RuntimeError {
+    let reason = match code {
+        1 => "duration must be positive",
+        2 => "array size must be positive",
+        3 => "division by zero",
+        4 => "cannot compute the square root of a negative number",
+        5 => "allocated memory size must be positive",
+        6 => "heap overflow",
+        7 => "illegal pixel coordinates",
+        8 => "illegal line coordinates",
+        9 => "illegal rectangle coordinates",
+        12 => "illegal center coordinates",
+        13 => "illegal radius",
+        14 => "maximum length must be non-negative",
+        15 | 16 => "string index out of bounds",
+        17 => "string is full",
+        18 => "string is empty",
+        19 => "insufficient string capacity",
+        20 => "illegal cursor location",
+        _ => "",
+    };
+    vm.halt();
+    match reason {
+        "" => vm.error(format!("ERR{code}")),
+        _ => vm.error(format!("ERR{code}: {reason}")),
+    }
+}
+
+fn address(vm: &VmInterpreter, base: i16, offset: i16) -> Result<usize, RuntimeError> {
+    let address = base as i32 + offset as i32;
+    if (0..RAM_SIZE as i32).contains(&address) {
+        Ok(address as usize)
+    } else {
+        Err(vm.error(format!("{address} is outside of RAM")))
+    }
+}
+
+fn read(vm: &VmInterpreter, base: i16, offset: i16) -> Result<i16, RuntimeError> {
+    Ok(vm.ram()[address(vm, base, offset)?])
+}
+
+fn write(vm: &mut VmInterpreter, base: i16, offset: i16, value: i16) -> Result<(), RuntimeError> {
+    let address = address(vm, base, offset)?;
+    vm.ram_mut()[address] = value;
+    Ok(())
+}
+
+fn divide(vm: &mut VmInterpreter, a: &[i16]) -> Result<i16, RuntimeError> {
+    match a[1] {
+        0 => Err(os_error(vm, 3)),
+        _ => Ok(a[0].wrapping_div(a[1])),
+    }
+}
+
+fn sqrt(vm: &mut VmInterpreter, a: &[i16]) -> Result<i16, RuntimeError> {
+    if a[0] < 0 {
+        return Err(os_error(vm, 4));
+    }
+    Ok((a[0] as f64).sqrt() as i16)
+}
+
+fn memory_init(vm: &mut VmInterpreter, _: &[i16]) -> Result<i16, RuntimeError> {
+    vm.os.free = BTreeMap::from([(HEAP_START, HEAP_END - HEAP_START)]);
+    vm.os.allocated.clear();
+    Ok(0)
+}
+
+// First fit, the rest of the block stays free
+fn alloc(vm: &mut VmInterpreter, a: &[i16]) -> Result<i16, RuntimeError> {
+    let size = a[0];
+    if size <= 0 {
+        return Err(os_error(vm, 5));
+    }
+    let found = vm.os.free.iter().find(|(_, free)| **free >= size);
+    let Some((&start, &free)) = found else {
+        return Err(os_error(vm, 6));
+    };
+    vm.os.free.remove(&start);
+    if free > size {
+        vm.os.free.insert(start + size, free - size);
+    }
+    vm.os.allocated.insert(start, size);
+    Ok(start)
+}
+
+// Merges the block with the free ones either side of it
+fn de_alloc(vm: &mut VmInterpreter, a: &[i16]) -> Result<i16, RuntimeError> {
+    let Some(mut size) = vm.os.allocated.remove(&a[0]) else {
+        return Ok(0);
+    };
+    let mut start = a[0];
+    if let Some(next) = vm.os.free.remove(&(start + size)) {
+        size += next;
+    }
+    if let Some((&before, &free)) = vm.os.free.range(..start).next_back() {
+        if before + free == start {
+            vm.os.free.remove(&before);
+            start = before;
+            size += free;
+        }
+    }
+    vm.os.free.insert(start, size);
+    Ok(0)
+}
+
+fn array_new(vm: &mut VmInterpreter, a: &[i16]) -> Result<i16, RuntimeError> {
+    if a[0] <= 0 {
+        return Err(os_error(vm, 2));
+    }
+    vm.invoke("Memory.alloc", a)
+}
+
+// Strings are laid out as their maximum length, their length and then the characters
+fn string_new(vm: &mut VmInterpreter, a: &[i16]) -> Result<i16, RuntimeError> {
+    let max = a[0];
+    if max < 0 {
+        return Err(os_error(vm, 14));
+    }
+    let this = vm.invoke("Memory.alloc", &[max.saturating_add(2)])?;
+    write(vm, this, 0, max)?;
+    write(vm, this, 1, 0)?;
+    Ok(this)
+}
+
+fn char_at(vm: &mut VmInterpreter, a: &[i16]) -> Result<i16, RuntimeError> {
+    let (this, j) = (a[0], a[1]);
+    if j < 0 || j >= read(vm, this, 1)? {
+        return Err(os_error(vm, 15));
+    }
+    read(vm, this, j + 2)
+}
+
+fn set_char_at(vm: &mut VmInterpreter, a: &[i16]) -> Result<i16, RuntimeError> {
+    let (this, j, c) = (a[0], a[1], a[2]);
+    if j < 0 || j >= read(vm, this, 1)? {
+        return Err(os_error(vm, 16));
+    }
+    write(vm, this, j + 2, c).map(|_| 0)
+}
+
+fn append_char(vm: &mut VmInterpreter, a: &[i16]) -> Result<i16, RuntimeError> {
+    let (this, c) = (a[0], a[1]);
+    let length = read(vm, this, 1)?;
+    if length >= read(vm, this, 0)? {
+        return Err(os_error(vm, 17));
+    }
+    write(vm, this, length + 2, c)?;
+    write(vm, this, 1, length + 1)?;
+    Ok(this)
+}
+
+fn erase_last_char(vm: &mut VmInterpreter, a: &[i16]) -> Result<i16, RuntimeError> {
+    let length = read(vm, a[0], 1)?;
+    if length == 0 {
+        return Err(os_error(vm, 18));
+    }
+    write(vm, a[0], 1, length - 1).map(|_| 0)
+}
+
+// The number at the start of the string, if there is one
+fn int_value(vm: &mut VmInterpreter, a: &[i16]) -> Result<i16, RuntimeError> {
+    let this = a[0];
+    let length = read(vm, this, 1)?;
+    let mut value: i16 = 0;
+    let mut negative = false;
+    for j in 0..length {
+        let c = read(vm, this, j + 2)?;
+        match c {
+            45 if j == 0 => negative = true,
+            48..=57 => value = value.wrapping_mul(10).wrapping_add(c - 48),
+            _ => break,
+        }
+    }
+    Ok(if negative {
+        value.wrapping_neg()
+    } else {
+        value
+    })
+}
+
+fn set_int(vm: &mut VmInterpreter, a: &[i16]) -> Result<i16, RuntimeError> {
+    let (this, n) = (a[0], a[1]);
+    let digits = n.to_string();
+    if digits.len() as i16 > read(vm, this, 0)? {
+        return Err(os_error(vm, 19));
+    }
+    for (j, c) in digits.bytes().enumerate() {
+        write(vm, this, j as i16 + 2, c as i16)?;
+    }
+    write(vm, this, 1, digits.len() as i16).map(|_| 0)
+}
+
+// The output stream doesn't have a cursor, only the arguments are checked
+fn move_cursor(vm: &mut VmInterpreter, a: &[i16]) -> Result<i16, RuntimeError> {
+    if !(0..23).contains(&a[0]) || !(0..64).contains(&a[1]) {
+        return Err(os_error(vm, 20));
+    }
+    Ok(0)
+}
+
+fn print_char(vm: &mut VmInterpreter, a: &[i16]) -> Result<i16, RuntimeError> {
+    let text = match a[0] {
+        NEW_LINE => String::from("\n"),
+        BACKSPACE => String::from("\x08"),
+        c @ 32..=126 => char::from(c as u8).to_string(),
+        // Nothing to show for anything outside the character set
+        _ => return Ok(0),
+    };
+    let written = vm
+        .os
+        .output
+        .write_all(text.as_bytes())
+        .and_then(|_| vm.os.output.flush());
+    written.map_err(|e| vm.error(format!("couldn't print: {e}")))?;
+    Ok(0)
+}
+
+fn print_string(vm: &mut VmInterpreter, a: &[i16]) -> Result<i16, RuntimeError> {
+    let length = vm.invoke("String.length", a)?;
+    for j in 0..length {
+        let c = vm.invoke("String.charAt", &[a[0], j])?;
+        vm.invoke("Output.printChar", &[c])?;
+    }
+    Ok(0)
+}
+
+fn print_int(vm: &mut VmInterpreter, a: &[i16]) -> Result<i16, RuntimeError> {
+    for c in a[0].to_string().bytes() {
+        vm.invoke("Output.printChar", &[c as i16])?;
+    }
+    Ok(0)
+}
+
+fn set_color(vm: &mut VmInterpreter, a: &[i16]) -> Result<i16, RuntimeError> {
+    vm.os.color = a[0] != 0;
+    Ok(0)
+}
+
+fn clear_screen(vm: &mut VmInterpreter, _: &[i16]) -> Result<i16, RuntimeError> {
+    vm.ram_mut()[SCREEN..KBD].fill(0);
+    Ok(0)
+}
+
+fn on_screen(x: i16, y: i16) -> bool {
+    (0..SCREEN_WIDTH).contains(&x) && (0..SCREEN_HEIGHT).contains(&y)
+}
+
+// Callers check the coordinates first
+fn set_pixel(vm: &mut VmInterpreter, x: i16, y: i16) {
+    let address = SCREEN + y as usize * 32 + x as usize / 16;
+    let bit = 1 << (x % 16);
+    if vm.os.color {
+        vm.ram_mut()[address] |= bit;
+    } else {
+        vm.ram_mut()[address] &= !bit;
+    }
+}
+
+fn draw_pixel(vm: &mut VmInterpreter, a: &[i16]) -> Result<i16, RuntimeError> {
+    if !on_screen(a[0], a[1]) {
+        return Err(os_error(vm, 7));
+    }
+    set_pixel(vm, a[0], a[1]);
+    Ok(0)
+}
+
+fn draw_line(vm: &mut VmInterpreter, a: &[i16]) -> Result<i16, RuntimeError> {
+    let (mut x, mut y, x2, y2) = (a[0], a[1], a[2], a[3]);
+    if !on_screen(x, y) || !on_screen(x2, y2) {
+        return Err(os_error(vm, 8));
+    }
+    // Bresenham's, in whichever direction the line goes
+    let (dx, dy) = ((x2 - x).abs(), -(y2 - y).abs());
+    let (step_x, step_y) = ((x2 - x).signum(), (y2 - y).signum());
+    let mut error = dx + dy;
+    loop {
+        set_pixel(vm, x, y);
+        if x == x2 && y == y2 {
+            return Ok(0);
+        }
+        if 2 * error >= dy {
+            error += dy;
+            x += step_x;
+        }
+        if 2 * error <= dx {
+            error += dx;
+            y += step_y;
+        }
+    }
+}
+
+fn draw_rectangle(vm: &mut VmInterpreter, a: &[i16]) -> Result<i16, RuntimeError> {
+    let (x1, y1, x2, y2) = (a[0], a[1], a[2], a[3]);
+    if !on_screen(x1, y1) || !on_screen(x2, y2) || x1 > x2 || y1 > y2 {
+        return Err(os_error(vm, 9));
+    }
+    for y in y1..=y2 {
+        for x in x1..=x2 {
+            set_pixel(vm, x, y);
+        }
+    }
+    Ok(0)
+}
+
+// Filled, and clipped to the edges of the screen
+fn draw_circle(vm: &mut VmInterpreter, a: &[i16]) -> Result<i16, RuntimeError> {
+    let (cx, cy, r) = (a[0], a[1], a[2]);
+    if !on_screen(cx, cy) {
+        return Err(os_error(vm, 12));
+    }
+    if !(0..=181).contains(&r) {
+        return Err(os_error(vm, 13));
+    }
+    for dy in -r..=r {
+        let half = ((r as i32 * r as i32 - dy as i32 * dy as i32) as f64).sqrt() as i16;
+        for x in cx - half..=cx + half {
+            if on_screen(x, cy + dy) {
+                set_pixel(vm, x, cy + dy);
+            }
+        }
+    }
+    Ok(0)
+}
+
+// The next key from the keyboard stream, None once it runs out
+fn next_key(vm: &mut VmInterpreter) -> Result<Option<i16>, RuntimeError> {
+    let mut byte = [0];
+    loop {
+        match vm.os.keyboard.read(&mut byte) {
+            Ok(0) => return Ok(None),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(vm.error(format!("couldn't read the keyboard: {e}"))),
+        }
+        let key = match byte[0] {
+            b'\r' => continue,
+            b'\n' => NEW_LINE,
+            8 | 127 => BACKSPACE,
+            b => b as i16,
+        };
+        return Ok(Some(key));
+    }
+}
+
+// Every call takes the next key from the stream, as if it was pressed and released
+fn key_pressed(vm: &mut VmInterpreter, _: &[i16]) -> Result<i16, RuntimeError> {
+    let key = next_key(vm)?.unwrap_or(0);
+    vm.ram_mut()[KBD] = key;
+    Ok(key)
+}
+
+fn read_char(vm: &mut VmInterpreter, _: &[i16]) -> Result<i16, RuntimeError> {
+    let Some(key) = next_key(vm)? else {
+        vm.halt();
+        return Err(vm.error(String::from(
+            "waiting for a key, but the keyboard input ran out",
+        )));
+    };
+    vm.invoke("Output.printChar", &[key])?;
+    Ok(key)
+}
+
+fn read_line(vm: &mut VmInterpreter, a: &[i16]) -> Result<i16, RuntimeError> {
+    vm.invoke("Output.printString", a)?;
+    let line = vm.invoke("String.new", &[LINE_LENGTH])?;
+    loop {
+        match vm.invoke("Keyboard.readChar", &[])? {
+            NEW_LINE => return Ok(line),
+            BACKSPACE => {
+                if vm.invoke("String.length", &[line])? > 0 {
+                    vm.invoke("String.eraseLastChar", &[line])?;
+                }
+            }
+            c => {
+                vm.invoke("String.appendChar", &[line, c])?;
+            }
+        }
+    }
+}
+
+fn read_int(vm: &mut VmInterpreter, a: &[i16]) -> Result<i16, RuntimeError> {
+    let line = vm.invoke("Keyboard.readLine", a)?;
+    let value = vm.invoke("String.intValue", &[line])?;
+    vm.invoke("String.dispose", &[line])?;
+    Ok(value)
+}
+
+fn sys_init(vm: &mut VmInterpreter, _: &[i16]) -> Result<i16, RuntimeError> {
+    for class in ["Memory", "Math", "Screen", "Output", "Keyboard"] {
+        vm.invoke(&format!("{class}.init"), &[])?;
+    }
+    vm.invoke("Main.main", &[])?;
+    vm.invoke("Sys.halt", &[])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{compile_str, vm_writer::parse_vm};
+
+    // Output that can still be read after it's handed to the interpreter
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run(
+        sources: &[&str],
+        keyboard: &'static str,
+    ) -> (VmInterpreter, Result<(), RuntimeError>, String) {
+        let output = Shared::default();
+        let mut vm = VmInterpreter::new()
+            .with_max_steps(100_000)
+            .with_output(output.clone())
+            .with_keyboard(keyboard.as_bytes());
+        for source in sources {
+            let program = compile_str(source).unwrap();
+            vm.load(&program.class_name, &program.commands).unwrap();
+        }
+        let result = vm.run();
+        let text = String::from_utf8(output.0.take()).unwrap();
+        (vm, result, text)
+    }
+
+    #[test]
+    fn test_programs_run_on_the_native_os() {
+        let main = "class Main { function void main() { var int n; var Array a; var String s; \
+                    let n = Keyboard.readInt(\"n? \"); \
+                    let a = Array.new(n); let a[n - 1] = Math.sqrt(n * n) / 2; \
+                    do Output.printString(\"half: \"); do Output.printInt(a[n - 1]); do Output.println(); \
+                    let s = String.new(6); do s.setInt(-1234); do s.appendChar(33); \
+                    do Output.printString(s); do Output.printInt(s.intValue() - (7 % 4)); \
+                    do Screen.drawRectangle(0, 0, 16, 1); do Sys.halt(); \
+                    do Output.printString(\"unreachable\"); return; } }";
+        let (vm, result, output) = run(&[main], "4x\x08\r\n");
+        assert_eq!(result, Ok(()));
+        assert!(vm.halted());
+        assert_eq!(output, "n? 4x\x08\nhalf: 2\n-1234!-1237");
+        let screen = vm.screen();
+        assert_eq!(&screen[..3], [-1, 1, 0]);
+        assert_eq!(&screen[32..35], [-1, 1, 0]);
+        assert_eq!(screen[64], 0);
+
+        let (_, result, _) = run(
+            &["class Main { function void main() { do Output.printInt(1 / 0); return; } }"],
+            "",
+        );
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Main.vm command 4 `call Math.divide 2`: ERR3: division by zero"
+        );
+    }
+
+    #[test]
+    fn test_os_can_be_replaced() {
+        let main = "class Main { function void main() { do Output.printInt(Math.multiply(6, 7)); \
+                    do Output.printInt(3 * 3); return; } }";
+        let (mut vm, _, _) = run(&[main], "");
+        let output = Shared::default();
+        vm = vm.with_output(output.clone());
+        let math = parse_vm("function Math.multiply 0\npush constant 1\nreturn").unwrap();
+        vm.load("Math", &math).unwrap();
+        assert_eq!(vm.run(), Ok(()));
+        assert_eq!(String::from_utf8(output.0.take()).unwrap(), "11");
+    }
+}
@@ -0,0 +1,120 @@
+// The Jack OS as the compiler sees it, declarations only
+// Classes of the program itself, or ones loaded with --os-api, replace these by name
+pub const OS_CLASSES: [&str; 8] = [
+    "class Math {
+        function void init() {}
+        function int abs(int x) {}
+        function int multiply(int x, int y) {}
+        function int divide(int x, int y) {}
+        function int min(int x, int y) {}
+        function int max(int x, int y) {}
+        function int sqrt(int x) {}
+    }",
+    "class String {
+        constructor String new(int maxLength) {}
+        method void dispose() {}
+        method int length() {}
+        method char charAt(int j) {}
+        method void setCharAt(int j, char c) {}
+        method String appendChar(char c) {}
+        method void eraseLastChar() {}
+        method int intValue() {}
+        method void setInt(int j) {}
+        function char backSpace() {}
+        function char doubleQuote() {}
+        function char newLine() {}
+    }",
+    "class Array {
+        function Array new(int size) {}
+        method void dispose() {}
+    }",
+    "class Output {
+        function void init() {}
+        function void moveCursor(int i, int j) {}
+        function void printChar(char c) {}
+        function void printString(String s) {}
+        function void printInt(int i) {}
+        function void println() {}
+        function void backSpace() {}
+    }",
+    "class Screen {
+        function void init() {}
+        function void clearScreen() {}
+        function void setColor(boolean b) {}
+        function void drawPixel(int x, int y) {}
+        function void drawLine(int x1, int y1, int x2, int y2) {}
+        function void drawRectangle(int x1, int y1, int x2, int y2) {}
+        function void drawCircle(int x, int y, int r) {}
+    }",
+    "class Keyboard {
+        function void init() {}
+        function char keyPressed() {}
+        function char readChar() {}
+        function String readLine(String message) {}
+        function int readInt(String message) {}
+    }",
+    "class Memory {
+        function void init() {}
+        function int peek(int address) {}
+        function void poke(int address, int value) {}
+        function Array alloc(int size) {}
+        function void deAlloc(Array o) {}
+    }",
+    "class Sys {
+        function void init() {}
+        function void halt() {}
+        function void error(int errorCode) {}
+        function void wait(int duration) {}
+    }",
+];
+
+#[cfg(test)]
+mod tests {
+    use crate::{CompilationEngine, CompilationError};
+
+    fn call_errors(engine: &mut CompilationEngine, statements: &str) -> Vec<CompilationError> {
+        let source = format!("class Main {{ function void main() {{ {statements} return; }} }}");
+        match engine.compile_str(&source) {
+            Ok(program) => program.warnings,
+            Err(errors) => errors,
+        }
+        .into_iter()
+        .map(|d| d.error)
+        // Unused variables and the like don't matter here
+        .filter(|e| !e.code().starts_with('W'))
+        .collect()
+    }
+
+    #[test]
+    fn test_os_calls_are_checked() {
+        let mut engine = CompilationEngine::new();
+        let statements = "var String s; var char c; let s = String.new(3); let c = s.charAt(0); \
+                          do Output.printString(s); do Screen.drawCircle(1, 2, 3); \
+                          do Sys.wait(Keyboard.readInt(\"?\")); do Memory.deAlloc(s);";
+        assert!(call_errors(&mut engine, statements).is_empty());
+
+        let errors = call_errors(
+            &mut engine,
+            "var char c; do Output.printInt(1, 2); do Outptu.println(); let c = Math.sqrt(\"4\");",
+        );
+        assert!(matches!(
+            errors.as_slice(),
+            [
+                CompilationError::ArgumentCount { .. },
+                CompilationError::UnknownClass(_),
+                CompilationError::TypeMismatch { .. },
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_os_can_be_overridden() {
+        let mut engine = CompilationEngine::new();
+        engine.index_str("class Math { function int multiply(int x, int y, int z) { return 0; } }");
+        let errors = call_errors(&mut engine, "do Math.multiply(1, 2, 3); do Math.abs(1);");
+        assert!(matches!(
+            errors.as_slice(),
+            [CompilationError::UnknownSubroutine { .. }]
+        ));
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+
+use crate::ast::{Class, Subroutine, SubroutineKind, Type};
+
+// What is known about a subroutine that can be called
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub kind: SubroutineKind,
+    // None for void
+    pub return_type: Option<Type>,
+    pub params: Vec<Type>,
+}
+
+impl Signature {
+    pub fn of(subroutine: &Subroutine) -> Self {
+        Signature {
+            kind: subroutine.kind,
+            return_type: subroutine.return_type.clone(),
+            params: subroutine
+                .params
+                .iter()
+                .map(|p| p.var_type.clone())
+                .collect(),
+        }
+    }
+}
+
+// Every class in the program and the subroutines it declares,
+// collected before compiling so calls between classes can be checked
+#[derive(Debug, Clone, Default)]
+pub struct ProgramIndex {
+    classes: HashMap<String, HashMap<String, Signature>>,
+}
+
+impl ProgramIndex {
+    // Replaces anything previously known about the class
+    pub fn add_class(&mut self, class: &Class) {
+        let subroutines = class
+            .subroutines
+            .iter()
+            .map(|s| (s.name.name.clone(), Signature::of(s)))
+            .collect();
+        self.classes.insert(class.name.name.clone(), subroutines);
+    }
+
+    pub fn has_class(&self, class: &str) -> bool {
+        self.classes.contains_key(class)
+    }
+
+    pub fn get(&self, class: &str, subroutine: &str) -> Option<&Signature> {
+        self.classes.get(class)?.get(subroutine)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{CompilationEngine, CompilationError};
+
+    #[test]
+    fn test_calls_between_classes() {
+        let mut engine = CompilationEngine::new();
+        engine.index_str(
+            "class Foo { constructor Foo new() { return this; } \
+             method int get(int i) { return i; } function void bar(int a, int b) { return; } }",
+        );
+        let source = "class Main { function void main() { var Foo f; \
+                      let f = Foo.new(); do f.get(1); do Foo.bar(1, 2); do Unknown.f(); \
+                      do Foo.baz(); do Foo.bar(1); do f.bar(1, 2); do Foo.get(1); \
+                      do main(); return; } }";
+        let errors: Vec<_> = engine
+            .compile_str(source)
+            .unwrap_err()
+            .into_iter()
+            .map(|d| d.error.to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "cannot find class `Unknown`",
+                "class `Foo` has no subroutine named `baz`",
+                "`Foo.bar` takes 2 arguments but 1 was supplied",
+                "`Foo.bar` is a function and can't be called on an object",
+                "`Foo.get` is a method and has to be called on an object",
+                "`Main.main` is a function and can't be called on an object",
+            ]
+        );
+
+        // Classes can refer to each other no matter what order they're compiled in
+        let mut engine = CompilationEngine::new();
+        engine.index_str("class A { function void f() { do B.g(); return; } }");
+        let errors = engine
+            .compile_str("class B { function void h() { do A.f(1); return; } }")
+            .unwrap_err();
+        assert!(matches!(
+            errors[0].error,
+            CompilationError::ArgumentCount { .. }
+        ));
+    }
+}
@@ -0,0 +1,37 @@
+use std::io::{self, Write};
+
+// The Hack screen as found in RAM from address 16384, 32 words to a row,
+// with the leftmost pixel of each word in its lowest bit and 1 for black
+pub const WIDTH: usize = 512;
+pub const HEIGHT: usize = 256;
+pub const WORDS: usize = WIDTH / 16 * HEIGHT;
+
+pub fn pixel(screen: &[i16], x: usize, y: usize) -> bool {
+    screen[y * WIDTH / 16 + x / 16] >> (x % 16) & 1 == 1
+}
+
+// Binary PBM, which most image viewers and converters can open
+pub fn write_pbm(screen: &[i16], mut out: impl Write) -> io::Result<()> {
+    write!(out, "P4\n{WIDTH} {HEIGHT}\n")?;
+    // Each word becomes two bytes with the leftmost pixel in the highest bit
+    let bytes: Vec<u8> = screen[..WORDS]
+        .iter()
+        .flat_map(|word| (*word as u16).reverse_bits().to_be_bytes())
+        .collect();
+    out.write_all(&bytes)?;
+    out.flush()
+}
+
+// Binary PPM, for tools that only take colour images
+pub fn write_ppm(screen: &[i16], mut out: impl Write) -> io::Result<()> {
+    write!(out, "P6\n{WIDTH} {HEIGHT}\n255\n")?;
+    let mut bytes = Vec::with_capacity(WIDTH * HEIGHT * 3);
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let shade = if pixel(screen, x, y) { 0 } else { 255 };
+            bytes.extend([shade; 3]);
+        }
+    }
+    out.write_all(&bytes)?;
+    out.flush()
+}
@@ -1,6 +1,11 @@
 use std::{collections::HashMap, fmt::Display};
 
-use crate::{compilation_engine::CompilationError, vm_writer::MemSegment};
+use crate::{
+    ast::{Class, Subroutine, SubroutineKind},
+    compilation_engine::CompilationError,
+    tokenizer::Span,
+    vm_writer::MemSegment,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Kind {
@@ -22,6 +27,16 @@ impl Display for Kind {
 }
 
 impl Kind {
+    // How the kind of variable is referred to in messages
+    pub fn describe(self) -> &'static str {
+        match self {
+            Kind::Static => "static variable",
+            Kind::Field => "field",
+            Kind::Arg => "argument",
+            Kind::Var => "local variable",
+        }
+    }
+
     pub fn to_mem_seg(self) -> MemSegment {
         match self {
             Kind::Static => MemSegment::Static,
@@ -43,12 +58,66 @@ pub struct SymbolTable {
     subroutine_lvl_table: HashMap<String, SymbolEntry>,
 }
 
+// Each declaration that was already taken, and where it was
+pub type Duplicates = Vec<(CompilationError, Span)>;
+
 impl SymbolTable {
+    // The statics and fields of a class, ready for its subroutines to be entered
+    pub fn for_class(class: &Class) -> (Self, Duplicates) {
+        let mut table = SymbolTable::default();
+        let mut duplicates = vec![];
+        for dec in &class.vars {
+            for name in &dec.names {
+                if let Err(e) = table.define(
+                    dec.kind,
+                    &dec.var_type.to_string(),
+                    name.name.clone(),
+                    name.span,
+                ) {
+                    duplicates.push((e, name.span));
+                }
+            }
+        }
+        (table, duplicates)
+    }
+
+    // Replaces the subroutine scope with the arguments and locals of this one,
+    // methods get `this` as their first argument
+    pub fn enter_subroutine(&mut self, class_name: &str, subroutine: &Subroutine) -> Duplicates {
+        self.start_subroutine();
+        let mut declarations = vec![];
+        if subroutine.kind == SubroutineKind::Method {
+            declarations.push((
+                Kind::Arg,
+                class_name.to_string(),
+                "this",
+                subroutine.name.span,
+            ));
+        }
+        for param in &subroutine.params {
+            let name = &param.name;
+            declarations.push((Kind::Arg, param.var_type.to_string(), &name.name, name.span));
+        }
+        for dec in &subroutine.vars {
+            for name in &dec.names {
+                declarations.push((Kind::Var, dec.var_type.to_string(), &name.name, name.span));
+            }
+        }
+        let mut duplicates = vec![];
+        for (kind, var_type, name, span) in declarations {
+            if let Err(e) = self.define(kind, &var_type, name.to_string(), span) {
+                duplicates.push((e, span));
+            }
+        }
+        duplicates
+    }
+
     pub fn define(
         &mut self,
         kind: Kind,
         type_of: &str,
         name: String,
+        span: Span,
     ) -> Result<(), CompilationError> {
         let (table, counter) = match kind {
             Kind::Static => (&mut self.class_lvl_table, &mut self.static_count),
@@ -63,12 +132,15 @@ impl SymbolTable {
                     var_type: String::from(type_of),
                     kind,
                     id: *counter,
+                    span,
+                    reads: 0,
+                    writes: 0,
                 },
             );
             *counter += 1;
             Ok(())
         } else {
-            Err(CompilationError::DuplicateIdentifier)
+            Err(CompilationError::DuplicateIdentifier(name))
         }
     }
 
@@ -81,16 +153,54 @@ impl SymbolTable {
         }
     }
 
+    // Locals and arguments hide fields and statics of the same name
     pub fn get(&self, name: &str) -> Option<&SymbolEntry> {
-        if let Some(e) = self.class_lvl_table.get(name) {
+        if let Some(e) = self.subroutine_lvl_table.get(name) {
+            Some(e)
+        } else if let Some(e) = self.class_lvl_table.get(name) {
+            Some(e)
+        } else {
+            None
+        }
+    }
+
+    fn get_mut(&mut self, name: &str) -> Option<&mut SymbolEntry> {
+        if let Some(e) = self.subroutine_lvl_table.get_mut(name) {
             Some(e)
-        } else if let Some(e) = self.subroutine_lvl_table.get(name) {
+        } else if let Some(e) = self.class_lvl_table.get_mut(name) {
             Some(e)
         } else {
             None
         }
     }
 
+    // Counts a use of the variable, returning it as it is afterwards
+    pub fn record_read(&mut self, name: &str) -> Option<&SymbolEntry> {
+        let entry = self.get_mut(name)?;
+        entry.reads += 1;
+        Some(entry)
+    }
+
+    pub fn record_write(&mut self, name: &str) -> Option<&SymbolEntry> {
+        let entry = self.get_mut(name)?;
+        entry.writes += 1;
+        Some(entry)
+    }
+
+    // Only the subroutine scope for Kind::Arg and Kind::Var
+    pub fn symbols(&self, kind: Kind) -> impl Iterator<Item = (&String, &SymbolEntry)> {
+        let table = match kind {
+            Kind::Static | Kind::Field => &self.class_lvl_table,
+            Kind::Arg | Kind::Var => &self.subroutine_lvl_table,
+        };
+        table.iter().filter(move |(_, e)| e.kind == kind)
+    }
+
+    // The field or static a local of the same name would hide
+    pub fn get_class_level(&self, name: &str) -> Option<&SymbolEntry> {
+        self.class_lvl_table.get(name)
+    }
+
     pub fn start_subroutine(&mut self) {
         self.subroutine_lvl_table.clear();
         self.arg_count = 0;
@@ -102,6 +212,10 @@ pub struct SymbolEntry {
     var_type: String,
     kind: Kind,
     id: i16,
+    // Where it was declared
+    span: Span,
+    reads: u16,
+    writes: u16,
 }
 
 impl SymbolEntry {
@@ -114,4 +228,64 @@ impl SymbolEntry {
     pub fn get_id(&self) -> i16 {
         self.id
     }
+    pub fn get_span(&self) -> Span {
+        self.span
+    }
+    pub fn get_reads(&self) -> u16 {
+        self.reads
+    }
+    pub fn get_writes(&self) -> u16 {
+        self.writes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn define(table: &mut SymbolTable, kind: Kind, name: &str) -> Result<(), CompilationError> {
+        table.define(kind, "int", String::from(name), Span::default())
+    }
+
+    #[test]
+    fn test_subroutine_scope_shadows_class_scope() {
+        let mut table = SymbolTable::default();
+        define(&mut table, Kind::Field, "x").unwrap();
+        define(&mut table, Kind::Static, "y").unwrap();
+        define(&mut table, Kind::Var, "x").unwrap();
+        define(&mut table, Kind::Arg, "y").unwrap();
+        assert_eq!(table.get("x").unwrap().get_kind(), Kind::Var);
+        assert_eq!(table.get("y").unwrap().get_kind(), Kind::Arg);
+
+        // Once the subroutine is over the class level ones are visible again
+        table.start_subroutine();
+        assert_eq!(table.get("x").unwrap().get_kind(), Kind::Field);
+        assert_eq!(table.get("y").unwrap().get_kind(), Kind::Static);
+        assert!(table.get("z").is_none());
+    }
+
+    #[test]
+    fn test_duplicates_and_usage() {
+        let mut table = SymbolTable::default();
+        define(&mut table, Kind::Var, "a").unwrap();
+        define(&mut table, Kind::Var, "b").unwrap();
+        assert!(matches!(
+            define(&mut table, Kind::Arg, "a"),
+            Err(CompilationError::DuplicateIdentifier(_))
+        ));
+        assert_eq!(table.get("b").unwrap().get_id(), 1);
+
+        table.record_write("a");
+        table.record_read("a");
+        let a = table.record_read("a").unwrap();
+        assert_eq!((a.get_reads(), a.get_writes()), (2, 1));
+        assert!(table.record_read("c").is_none());
+        let mut unread: Vec<_> = table
+            .symbols(Kind::Var)
+            .filter(|(_, e)| e.get_reads() == 0)
+            .map(|(name, _)| name.as_str())
+            .collect();
+        unread.sort();
+        assert_eq!(unread, ["b"]);
+    }
 }
@@ -0,0 +1,746 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    assembler::{assemble, from_hack, LineErrors},
+    cpu_emulator::CpuEmulator,
+    error::Error,
+    hack::RAM_SIZE,
+    vm_interpreter::VmInterpreter,
+    vm_translator::VmTranslator,
+    vm_writer::{parse_vm, VmCommand},
+    CompilationEngine,
+};
+
+// Scripts step through the program themselves, this only stops a program that runs away
+// inside a single step, like the whole of Sys.init when it runs natively
+const MAX_VM_STEPS: u64 = 50_000_000;
+
+// A value in the output, e.g. `RAM[256]%D2.6.2` is RAM[256] in decimal,
+// 6 characters wide with 2 spaces either side
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Column {
+    name: String,
+    format: char,
+    left: usize,
+    width: usize,
+    right: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Command {
+    Load(String),
+    OutputFile(String),
+    CompareTo(String),
+    OutputList(Vec<Column>),
+    Set(String, i16),
+    // Without a count it goes on forever, or until the program ends
+    Repeat(Option<u64>, Vec<Command>),
+    VmStep,
+    Tick,
+    Tock,
+    Output,
+    // Only matters for the course's GUI, e.g. echo and breakpoints
+    Ignored,
+}
+
+enum Machine {
+    Vm(Box<VmInterpreter>),
+    Cpu(CpuEmulator),
+}
+
+// Runs a course .tst script against the VM interpreter or the CPU emulator, whichever it
+// loads, writing its .out file and comparing it with the .cmp file line by line
+pub fn run_script(path: &Path) -> Result<(), Error> {
+    let source = fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+    let commands = parse(&source).map_err(|errors| Error::Syntax {
+        path: path.to_path_buf(),
+        errors,
+    })?;
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let mut runner = Runner {
+        path: path.to_path_buf(),
+        dir,
+        machine: None,
+        finished: false,
+        columns: vec![],
+        output: vec![],
+        output_file: None,
+        compare_to: None,
+    };
+    runner.run(&commands)?;
+    runner.finish()
+}
+
+fn tokenize(source: &str) -> Vec<(usize, String)> {
+    let mut tokens = vec![];
+    let mut chars = source.chars().peekable();
+    let mut line = 1;
+    let mut word = String::new();
+    let end_word = |word: &mut String, tokens: &mut Vec<(usize, String)>, line| {
+        if !word.is_empty() {
+            tokens.push((line, std::mem::take(word)));
+        }
+    };
+    while let Some(c) = chars.next() {
+        match c {
+            '/' if chars.peek() == Some(&'/') => {
+                end_word(&mut word, &mut tokens, line);
+                while chars.next_if(|c| *c != '\n').is_some() {}
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                end_word(&mut word, &mut tokens, line);
+                let mut last = ' ';
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        line += 1;
+                    }
+                    if last == '*' && c == '/' {
+                        break;
+                    }
+                    last = c;
+                }
+            }
+            '"' => {
+                end_word(&mut word, &mut tokens, line);
+                let text: String = std::iter::from_fn(|| chars.next_if(|c| *c != '"')).collect();
+                chars.next();
+                tokens.push((line, format!("\"{text}\"")));
+            }
+            ',' | ';' | '!' | '{' | '}' => {
+                end_word(&mut word, &mut tokens, line);
+                tokens.push((line, c.to_string()));
+            }
+            c if c.is_whitespace() => {
+                end_word(&mut word, &mut tokens, line);
+                if c == '\n' {
+                    line += 1;
+                }
+            }
+            c => word.push(c),
+        }
+    }
+    end_word(&mut word, &mut tokens, line);
+    tokens
+}
+
+fn parse(source: &str) -> Result<Vec<Command>, LineErrors> {
+    let tokens = tokenize(source);
+    let mut position = 0;
+    let commands = parse_block(&tokens, &mut position)?;
+    match tokens.get(position) {
+        Some((line, _)) => Err(vec![(*line, String::from("`}` without a matching `{`"))]),
+        None => Ok(commands),
+    }
+}
+
+// Commands up to the end of the script or the `}` closing the block
+fn parse_block(
+    tokens: &[(usize, String)],
+    position: &mut usize,
+) -> Result<Vec<Command>, LineErrors> {
+    let mut commands = vec![];
+    let mut words: Vec<&(usize, String)> = vec![];
+    while let Some(token) = tokens.get(*position) {
+        *position += 1;
+        match token.1.as_str() {
+            "," | ";" | "!" => {
+                if !words.is_empty() {
+                    commands.push(parse_command(&words)?);
+                    words.clear();
+                }
+            }
+            "{" => {
+                let line = token.0;
+                let count = match words.as_slice() {
+                    [(_, repeat)] if repeat == "repeat" => None,
+                    [(_, repeat), (_, n)] if repeat == "repeat" => {
+                        let n = n
+                            .parse()
+                            .map_err(|_| vec![(line, format!("`{n}` is not a count"))])?;
+                        Some(n)
+                    }
+                    _ => return Err(vec![(line, String::from("only repeat can have a block"))]),
+                };
+                words.clear();
+                let block = parse_block(tokens, position)?;
+                if tokens.get(*position - 1).is_none_or(|(_, t)| t != "}") {
+                    return Err(vec![(line, String::from("this block is never closed"))]);
+                }
+                commands.push(Command::Repeat(count, block));
+            }
+            "}" => {
+                if !words.is_empty() {
+                    commands.push(parse_command(&words)?);
+                }
+                return Ok(commands);
+            }
+            _ => words.push(token),
+        }
+    }
+    if !words.is_empty() {
+        commands.push(parse_command(&words)?);
+    }
+    Ok(commands)
+}
+
+fn parse_command(words: &[&(usize, String)]) -> Result<Command, LineErrors> {
+    let line = words[0].0;
+    let error = |message: String| vec![(line, message)];
+    let args: Vec<&str> = words[1..].iter().map(|(_, w)| w.as_str()).collect();
+    let command = match (words[0].1.as_str(), args.as_slice()) {
+        ("load", []) => Command::Load(String::new()),
+        ("load", [file]) => Command::Load(file.to_string()),
+        ("output-file", [file]) => Command::OutputFile(file.to_string()),
+        ("compare-to", [file]) => Command::CompareTo(file.to_string()),
+        ("output-list", columns) => Command::OutputList(
+            columns
+                .iter()
+                .map(|c| {
+                    parse_column(c)
+                        .ok_or_else(|| error(format!("`{c}` is not a valid output column")))
+                })
+                .collect::<Result<_, _>>()?,
+        ),
+        ("set", [name, value]) => Command::Set(
+            name.to_string(),
+            parse_value(value).ok_or_else(|| error(format!("`{value}` is not a valid value")))?,
+        ),
+        ("vmstep", []) => Command::VmStep,
+        ("tick", []) => Command::Tick,
+        ("tock", []) => Command::Tock,
+        ("ticktock", []) => Command::Repeat(Some(1), vec![Command::Tick, Command::Tock]),
+        ("output", []) => Command::Output,
+        ("echo" | "clear-echo" | "breakpoint" | "clear-breakpoints", _) => Command::Ignored,
+        (name, _) => {
+            return Err(error(format!(
+                "`{name}` is not a command, or has the wrong arguments"
+            )))
+        }
+    };
+    Ok(command)
+}
+
+// The format is optional and defaults to 16 binary digits
+fn parse_column(column: &str) -> Option<Column> {
+    let (name, format) = column.split_once('%').unwrap_or((column, "B1.16.1"));
+    let mut chars = format.chars();
+    let kind = chars.next().filter(|c| "BDXS".contains(*c))?;
+    let sizes: Vec<usize> = chars
+        .as_str()
+        .split('.')
+        .map(|n| n.parse().ok())
+        .collect::<Option<_>>()?;
+    let [left, width, right] = sizes[..] else {
+        return None;
+    };
+    Some(Column {
+        name: name.to_string(),
+        format: kind,
+        left,
+        width,
+        right,
+    })
+}
+
+// Decimal, or %X, %B and %D for hex, binary and decimal
+fn parse_value(value: &str) -> Option<i16> {
+    let (radix, digits) = match value.strip_prefix('%') {
+        Some(rest) => match rest.split_at_checked(1)? {
+            ("X", digits) => (16, digits),
+            ("B", digits) => (2, digits),
+            ("D", digits) => (10, digits),
+            _ => return None,
+        },
+        None => (10, value),
+    };
+    match radix {
+        10 => digits
+            .parse::<i32>()
+            .ok()
+            .filter(|n| (-32768..=65535).contains(n))
+            .map(|n| n as i16),
+        _ => u16::from_str_radix(digits, radix).ok().map(|n| n as i16),
+    }
+}
+
+impl Column {
+    // Names that don't fit are cut short, the same as the course's tools do
+    fn header(&self) -> String {
+        let total = self.left + self.width + self.right;
+        let name: String = self.name.chars().take(total).collect();
+        let before = (total - name.len()) / 2;
+        format!(
+            "{}{name}{}",
+            " ".repeat(before),
+            " ".repeat(total - name.len() - before)
+        )
+    }
+
+    fn cell(&self, value: i16) -> String {
+        let text = match self.format {
+            'B' => format!("{:016b}", value as u16),
+            'X' => format!("{:04X}", value as u16),
+            _ => value.to_string(),
+        };
+        // Only the lowest digits fit, the same as the course's tools
+        let text = &text[text.len().saturating_sub(self.width)..];
+        format!(
+            "{}{text:>width$}{}",
+            " ".repeat(self.left),
+            " ".repeat(self.right),
+            width = self.width
+        )
+    }
+}
+
+struct Runner {
+    path: PathBuf,
+    dir: PathBuf,
+    machine: Option<Machine>,
+    // Set once the VM program has nothing left to run
+    finished: bool,
+    columns: Vec<Column>,
+    output: Vec<String>,
+    output_file: Option<PathBuf>,
+    compare_to: Option<PathBuf>,
+}
+
+impl Runner {
+    fn run(&mut self, commands: &[Command]) -> Result<(), Error> {
+        for command in commands {
+            match command {
+                Command::Load(file) => self.load(file)?,
+                Command::OutputFile(file) => self.output_file = Some(self.dir.join(file)),
+                Command::CompareTo(file) => self.compare_to = Some(self.dir.join(file)),
+                Command::OutputList(columns) => {
+                    self.columns = columns.clone();
+                    let cells: Vec<_> = columns.iter().map(Column::header).collect();
+                    self.output.push(format!("|{}|", cells.join("|")));
+                }
+                Command::Set(name, value) => {
+                    let address = self.variable(name)?;
+                    self.set(address, *value)?;
+                }
+                Command::Repeat(count, block) => {
+                    let mut i = 0;
+                    while count.is_none_or(|n| i < n) && !self.finished {
+                        self.run(block)?;
+                        i += 1;
+                    }
+                }
+                Command::VmStep => match self.machine.as_mut() {
+                    Some(Machine::Vm(vm)) => {
+                        let running = vm.step().map_err(|source| Error::Runtime {
+                            path: self.path.clone(),
+                            source,
+                        })?;
+                        self.finished = !running;
+                    }
+                    _ => return Err(self.usage("vmstep needs a .vm program to be loaded")),
+                },
+                Command::Tick => {}
+                Command::Tock => match self.machine.as_mut() {
+                    Some(Machine::Cpu(cpu)) => cpu.step(),
+                    _ => return Err(self.usage("tock needs a .asm or .hack program to be loaded")),
+                },
+                Command::Output => {
+                    let mut cells = vec![];
+                    for column in &self.columns {
+                        let value = self.get(self.variable(&column.name)?)?;
+                        cells.push(column.cell(value));
+                    }
+                    self.output.push(format!("|{}|", cells.join("|")));
+                }
+                Command::Ignored => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn usage(&self, message: &str) -> Error {
+        Error::Usage(format!("{}: {message}", self.path.display()))
+    }
+
+    // Registers of the CPU, which aren't in RAM
+    const REGISTERS: [&'static str; 4] = ["A", "D", "PC", "time"];
+
+    // Where in RAM a variable is, registers are given addresses past the end of it
+    fn variable(&self, name: &str) -> Result<usize, Error> {
+        let invalid = || {
+            self.usage(&format!(
+                "`{name}` is not something that can be set or output"
+            ))
+        };
+        if let Some(i) = Self::REGISTERS.iter().position(|r| *r == name) {
+            return match self.machine {
+                Some(Machine::Cpu(_)) => Ok(usize::MAX - i),
+                _ => Err(invalid()),
+            };
+        }
+        let ram = |address: usize| match &self.machine {
+            Some(Machine::Vm(vm)) => vm.ram()[address],
+            Some(Machine::Cpu(cpu)) => cpu.ram()[address],
+            None => 0,
+        };
+        let pointers = ["sp", "local", "argument", "this", "that"];
+        if let Some(address) = pointers.iter().position(|p| *p == name) {
+            return Ok(address);
+        }
+        let (segment, index) = name
+            .strip_suffix(']')
+            .and_then(|n| n.split_once('['))
+            .ok_or_else(invalid)?;
+        let index: usize = index.parse().map_err(|_| invalid())?;
+        let address = match segment {
+            "RAM" => index,
+            "temp" => 5 + index,
+            _ => match pointers[1..].iter().position(|p| *p == segment) {
+                Some(pointer) => ram(pointer + 1) as u16 as usize + index,
+                None => return Err(invalid()),
+            },
+        };
+        if address >= RAM_SIZE {
+            return Err(invalid());
+        }
+        Ok(address)
+    }
+
+    fn get(&self, address: usize) -> Result<i16, Error> {
+        Ok(match &self.machine {
+            Some(Machine::Cpu(cpu)) => match usize::MAX - address {
+                0 => cpu.a(),
+                1 => cpu.d(),
+                2 => cpu.pc() as i16,
+                // Columns only hold 16 bits, a wrapped count would just be wrong
+                3 => i16::try_from(cpu.cycles())
+                    .map_err(|_| self.usage("`time` is past 32767 and can't be output"))?,
+                _ => cpu.ram()[address],
+            },
+            Some(Machine::Vm(vm)) if address < vm.ram().len() => vm.ram()[address],
+            Some(Machine::Vm(_)) => return Err(self.usage("the VM only has RAM to output")),
+            None => 0,
+        })
+    }
+
+    fn set(&mut self, address: usize, value: i16) -> Result<(), Error> {
+        match &mut self.machine {
+            Some(Machine::Cpu(cpu)) => match usize::MAX - address {
+                0 => cpu.set_a(value),
+                1 => cpu.set_d(value),
+                2 if value >= 0 => cpu.set_pc(value as u16),
+                2 => return Err(self.usage(&format!("`PC` can't be set to {value}"))),
+                3 => return Err(self.usage("`time` can't be set")),
+                _ if address < cpu.ram().len() => cpu.ram_mut()[address] = value,
+                _ => return Err(self.usage(&format!("RAM[{address}] is out of range"))),
+            },
+            Some(Machine::Vm(vm)) if address < vm.ram().len() => vm.ram_mut()[address] = value,
+            Some(Machine::Vm(_)) => return Err(self.usage("the VM only has RAM to set")),
+            None => return Err(self.usage("`set` needs a program to be loaded")),
+        }
+        Ok(())
+    }
+
+    // `load,` on its own loads every class in the script's directory
+    fn load(&mut self, file: &str) -> Result<(), Error> {
+        let path = self.dir.join(file);
+        self.finished = false;
+        let extension = path
+            .extension()
+            .and_then(|x| x.to_str())
+            .unwrap_or_default();
+        self.machine = Some(match extension {
+            "asm" | "hack" => Machine::Cpu(CpuEmulator::new(&self.machine_code(&path)?)),
+            "vm" => {
+                let commands = read_vm(&path)?;
+                self.start(vec![(class_name(&path), commands)], false)?
+            }
+            _ if path.is_dir() => {
+                let classes = program(&path)?;
+                self.start(classes, true)?
+            }
+            _ => return Err(self.usage(&format!("can't load {}", path.display()))),
+        });
+        Ok(())
+    }
+
+    // A whole program starts from Sys.init, the native one if it doesn't have its own,
+    // a single file from its first command unless it has a Sys.init
+    fn start(
+        &self,
+        classes: Vec<(String, Vec<VmCommand>)>,
+        program: bool,
+    ) -> Result<Machine, Error> {
+        let mut vm = VmInterpreter::new()
+            .with_max_steps(MAX_VM_STEPS)
+            .with_output(io::sink());
+        for (class_name, commands) in classes {
+            vm.load(&class_name, &commands)
+                .map_err(|source| Error::Runtime {
+                    path: self.path.clone(),
+                    source,
+                })?;
+        }
+        if program || vm.has_function("Sys.init") {
+            vm.start("Sys.init").map_err(|source| Error::Runtime {
+                path: self.path.clone(),
+                source,
+            })?;
+        }
+        Ok(Machine::Vm(Box::new(vm)))
+    }
+
+    // The program as it is, or else translated from the VM code and Jack next to it
+    fn machine_code(&self, path: &Path) -> Result<Vec<u16>, Error> {
+        let syntax = |path: &Path| {
+            let path = path.to_path_buf();
+            move |errors| Error::Syntax { path, errors }
+        };
+        if path.exists() {
+            let source = fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+            return match path.extension().and_then(|x| x.to_str()) {
+                Some("hack") => from_hack(&source).map_err(syntax(path)),
+                _ => assemble(&source).map_err(syntax(path)),
+            };
+        }
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let mut translator = VmTranslator::new();
+        for (class_name, commands) in program(dir)? {
+            translator.translate(&class_name, &commands);
+        }
+        let undefined = translator.undefined();
+        if !undefined.is_empty() {
+            return Err(Error::Link {
+                path: path.with_extension("asm"),
+                undefined,
+            });
+        }
+        assemble(&translator.finish()).map_err(syntax(&path.with_extension("asm")))
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        let output = self
+            .output
+            .iter()
+            .map(|line| format!("{line}\n"))
+            .collect::<String>();
+        if let Some(file) = &self.output_file {
+            fs::write(file, output).map_err(|e| Error::io(file, e))?;
+        }
+        let Some(file) = &self.compare_to else {
+            return Ok(());
+        };
+        let expected = fs::read_to_string(file).map_err(|e| Error::io(file, e))?;
+        let mut expected = expected.lines();
+        for (i, found) in self.output.iter().enumerate() {
+            let line = expected.next().unwrap_or_default().trim_end();
+            if !matches(line, found) {
+                return Err(Error::Comparison {
+                    path: self.path,
+                    line: i + 1,
+                    expected: line.to_string(),
+                    found: found.clone(),
+                });
+            }
+        }
+        // A script that stops short of what the compare file expects fails too
+        match expected.next().map(str::trim_end) {
+            Some(line) if !line.is_empty() => Err(Error::Comparison {
+                path: self.path,
+                line: self.output.len() + 1,
+                expected: line.to_string(),
+                found: String::new(),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+// `*` in the compare file stands for any character
+fn matches(expected: &str, found: &str) -> bool {
+    expected.len() == found.len()
+        && expected
+            .chars()
+            .zip(found.chars())
+            .all(|(e, f)| e == '*' || e == f)
+}
+
+fn class_name(path: &Path) -> String {
+    path.file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn read_vm(path: &Path) -> Result<Vec<VmCommand>, Error> {
+    let source = fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+    parse_vm(&source).map_err(|error| Error::Syntax {
+        path: path.to_path_buf(),
+        errors: vec![error],
+    })
+}
+
+// Every class in a directory, compiling the Jack ones in memory so the .vm files
+// that came out of them last time aren't needed, or used
+fn program(dir: &Path) -> Result<Vec<(String, Vec<VmCommand>)>, Error> {
+    let mut files = vec![];
+    for entry in dir.read_dir().map_err(|e| Error::io(dir, e))? {
+        files.push(entry.map_err(|e| Error::io(dir, e))?.path());
+    }
+    // Directory order isn't stable between platforms
+    files.sort();
+    let has_extension = |f: &PathBuf, x: &str| f.extension().is_some_and(|e| e == x);
+    let jack: Vec<_> = files
+        .iter()
+        .filter(|f| has_extension(f, "jack"))
+        .cloned()
+        .collect();
+
+    let mut classes = vec![];
+    let mut engine = CompilationEngine::new();
+    engine.index_files(&jack)?;
+    for file in &jack {
+        let source = fs::read_to_string(file).map_err(|e| Error::io(file, e))?;
+        let program = engine.compile_str(&source).map_err(Error::Compilation)?;
+        classes.push((program.class_name, program.commands));
+    }
+    for file in files.iter().filter(|f| has_extension(f, "vm")) {
+        if !jack.iter().any(|j| j.file_stem() == file.file_stem()) {
+            classes.push((class_name(file), read_vm(file)?));
+        }
+    }
+    Ok(classes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scripts(dir: &Path, found: &mut Vec<PathBuf>) {
+        for entry in dir.read_dir().unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                scripts(&path, found);
+            } else if path.extension().is_some_and(|x| x == "tst") {
+                found.push(path);
+            }
+        }
+    }
+
+    // Projects 7 and 8 against both the VM interpreter and the VM translator on the CPU
+    // emulator, and the OS tests of project 12 against the native OS
+    // Each runs from a copy of its directory so the .out files stay out of the source tree
+    #[test]
+    fn test_course_projects() {
+        let projects = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/projects");
+        let mut found = vec![];
+        scripts(&projects, &mut found);
+        found.sort();
+        assert!(found.len() >= 20);
+        let copies = std::env::temp_dir().join(format!(
+            "hack_jack_test_course_projects_{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&copies);
+        let mut failures = vec![];
+        for script in &found {
+            let dir = script.parent().unwrap();
+            let copy = copies.join(dir.strip_prefix(&projects).unwrap());
+            fs::create_dir_all(&copy).unwrap();
+            for entry in dir.read_dir().unwrap() {
+                let file = entry.unwrap().path();
+                fs::copy(&file, copy.join(file.file_name().unwrap())).unwrap();
+            }
+            if let Err(e) = run_script(&copy.join(script.file_name().unwrap())) {
+                failures.push(e.to_string());
+            }
+        }
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+    }
+
+    #[test]
+    fn test_script_output() {
+        let script = "load Add.vm, output-list RAM[0]%D2.6.2 RAM[256]%X1.4.1 sp time;\n\
+                      set sp 256, /* two steps */ repeat 2 { vmstep; } output; vmstep, output;";
+        let commands = parse(script).unwrap();
+        assert_eq!(commands.len(), 7);
+        assert_eq!(parse_column("RAM[256]%X1.4.1").unwrap().header(), "RAM[25");
+        assert_eq!(parse_column("sp").unwrap().cell(-2), " 1111111111111110 ");
+        assert_eq!(
+            parse_column("RAM[0]%D2.6.2").unwrap().header(),
+            "  RAM[0]  "
+        );
+        assert_eq!(parse_value("%XFFFF"), Some(-1));
+        assert_eq!(parse_value("%B101"), Some(5));
+        assert_eq!(
+            parse("repeat 3 {\nvmstep;\njump;\n}").unwrap_err(),
+            [(
+                3,
+                String::from("`jump` is not a command, or has the wrong arguments")
+            )]
+        );
+        assert!(matches("|  1*  |", "|  12  |"));
+        assert!(!matches("|  1*  |", "|  12 |"));
+
+        // Every line of the compare file has to be output
+        let dir = std::env::temp_dir().join(format!(
+            "hack_jack_test_script_output_{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("Add.vm"), "push constant 7\npush constant 8\nadd").unwrap();
+        fs::write(
+            dir.join("Add.tst"),
+            "load Add.vm, output-file Add.out, compare-to Add.cmp, \
+             output-list RAM[256]%D1.6.1; set sp 256, repeat 3 { vmstep; } output;",
+        )
+        .unwrap();
+        fs::write(dir.join("Add.cmp"), "|RAM[256]|\n|     15 |\n").unwrap();
+        run_script(&dir.join("Add.tst")).unwrap();
+        fs::write(dir.join("Add.cmp"), "|RAM[256]|\n|     15 |\n|     15 |\n").unwrap();
+        let error = run_script(&dir.join("Add.tst")).unwrap_err();
+        assert!(matches!(error, Error::Comparison { line: 3, .. }));
+    }
+
+    #[test]
+    fn test_cpu_registers() {
+        let dir = std::env::temp_dir().join(format!(
+            "hack_jack_test_cpu_registers_{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("Add.asm"), "@5\nD=D+A\n").unwrap();
+        let script = |commands: &str| {
+            fs::write(
+                dir.join("Add.tst"),
+                format!(
+                    "load Add.asm, output-file Add.out, compare-to Add.cmp, \
+                     output-list A%D1.2.1 D%D1.2.1 PC%D1.2.1 time%D1.2.1; {commands}"
+                ),
+            )
+            .unwrap();
+            run_script(&dir.join("Add.tst"))
+        };
+        fs::write(
+            dir.join("Add.cmp"),
+            "| A  | D  | PC |time|\n|  7 | 17 |  2 |  1 |\n",
+        )
+        .unwrap();
+        script("set A 7, set D 10, set PC 1, tick, tock, output;").unwrap();
+
+        let usage = |commands| match script(commands) {
+            Err(Error::Usage(message)) => message,
+            result => panic!("expected a usage error, got {result:?}"),
+        };
+        assert!(usage("set time 0;").ends_with("`time` can't be set"));
+        assert!(usage("set PC -1;").ends_with("`PC` can't be set to -1"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
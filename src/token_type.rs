@@ -61,6 +61,54 @@ impl Display for TokenType {
     }
 }
 
+// Binding strength of a binary operator when operator precedence is enabled, higher binds tighter
+// Jack itself has no precedence, so this is only a compiler option
+pub fn binary_precedence(op: char) -> Option<u8> {
+    if op != TokenType::BinaryOp {
+        return None;
+    }
+    match op {
+        '*' | '/' | '%' => Some(3),
+        '+' | '-' => Some(2),
+        '<' | '>' | '=' => Some(1),
+        _ => Some(0),
+    }
+}
+
+// What the parser was looking for when it found something else
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expected {
+    Keyword(Keyword),
+    Symbol(char),
+    Type(TokenType),
+}
+
+impl Display for Expected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expected::Keyword(k) => write!(f, "'{k}'"),
+            Expected::Symbol(c) => write!(f, "'{c}'"),
+            Expected::Type(t) => write!(f, "{t}"),
+        }
+    }
+}
+
+impl From<Keyword> for Expected {
+    fn from(k: Keyword) -> Self {
+        Expected::Keyword(k)
+    }
+}
+impl From<char> for Expected {
+    fn from(c: char) -> Self {
+        Expected::Symbol(c)
+    }
+}
+impl From<TokenType> for Expected {
+    fn from(t: TokenType) -> Self {
+        Expected::Type(t)
+    }
+}
+
 impl PartialEq<TokenType> for i16 {
     fn eq(&self, other: &TokenType) -> bool {
         other == &TokenType::Constant
@@ -1,44 +1,111 @@
 use crate::{compilation_engine::CompilationError, tokens::*};
 use std::collections::VecDeque;
 
-impl From<std::num::ParseIntError> for CompilationError {
-    fn from(_: std::num::ParseIntError) -> Self {
-        CompilationError::InvalidInt
+/// Location of a token in its source file
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    // Byte offsets of the first character and one past the last
+    pub start: usize,
+    pub end: usize,
+    // 1-based, columns are counted in characters
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Span {
+    // Covers everything from the start of this span to the end of the other
+    pub fn to(self, end: Span) -> Span {
+        Span {
+            end: end.end.max(self.end),
+            ..self
+        }
     }
 }
 
 #[derive(Debug, Default)]
 pub struct Tokenizer {
     chars: VecDeque<char>,
-    errors: Vec<CompilationError>,
+    offset: usize,
+    line: usize,
+    col: usize,
+    errors: Vec<(CompilationError, Span)>,
 }
 
 impl Tokenizer {
     pub fn new(file: String) -> Self {
-        let tknzr = Tokenizer {
-            chars: file.trim().chars().collect(),
-            errors: vec![],
-        };
-        tknzr
+        Tokenizer {
+            chars: file.chars().collect(),
+            line: 1,
+            col: 1,
+            ..Default::default()
+        }
+    }
+
+    // Every character is consumed through here so the position stays in sync
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.pop_front()?;
+        self.offset += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.front().copied()
+    }
+
+    // Consumes characters for as long as they match the predicate
+    fn take_while(&mut self, mut word: String, pred: impl Fn(char) -> bool) -> String {
+        while let Some(c) = self.peek() {
+            if !pred(c) {
+                break;
+            }
+            word.push(c);
+            self.bump();
+        }
+        word
+    }
+
+    // Empty span at the current position, used once the input is exhausted
+    pub fn eof_span(&self) -> Span {
+        Span {
+            start: self.offset,
+            end: self.offset,
+            line: self.line,
+            col: self.col,
+        }
     }
 
-    // Called when we have already seen a '/'
+    // Errors found while tokenizing, in the order they were encountered
+    pub fn take_errors(&mut self) -> Vec<(CompilationError, Span)> {
+        std::mem::take(&mut self.errors)
+    }
+
+    // Called when we have already seen a '/', which the span starts at
     // So we only care if the very next character is '/' or '*'
     // Advances to the next character after the comment before returning true
     // Otherwise returns false
-    fn is_comment(&mut self) -> bool {
-        match self.chars.get(0) {
+    fn is_comment(&mut self, mut span: Span) -> bool {
+        match self.peek() {
             Some('*') => {
-                while let Some(c) = self.chars.pop_front() {
-                    if c == '*' && self.chars.get(0) == Some(&'/') {
-                        self.chars.pop_front();
-                        break;
+                self.bump();
+                while let Some(c) = self.bump() {
+                    if c == '*' && self.peek() == Some('/') {
+                        self.bump();
+                        return true;
                     }
                 }
+                span.end = span.start + 2;
+                self.errors
+                    .push((CompilationError::UnterminatedComment, span));
                 true
             }
             Some('/') => {
-                while let Some(c) = self.chars.pop_front() {
+                while let Some(c) = self.bump() {
                     if c == '\n' {
                         break;
                     }
@@ -49,75 +116,66 @@ impl Tokenizer {
         }
     }
 
-    fn get_string(&mut self) -> Option<Token> {
-        let mut end = self.chars.len();
-        for (i, &c) in self.chars.iter().enumerate() {
-            if c == '"' {
-                end = i;
-                break;
-            }
+    // Called when we have already seen the opening '"', which the span starts at.
+    // Strings can't span lines, so a missing '"' only swallows the rest of its line
+    fn get_string(&mut self, mut span: Span) -> Token {
+        let s = self.take_while(String::new(), |c| c != '"' && c != '\n');
+        if self.peek() == Some('"') {
+            self.bump();
+        } else {
+            span.end = span.start + 1;
+            self.errors
+                .push((CompilationError::UnterminatedString, span));
         }
-        let s: String = self.chars.drain(..end).collect();
-        self.chars.pop_front();
-        Some(Token::StringConstant(s))
+        Token::StringConstant(s)
     }
 
-    pub fn advance(&mut self) -> Option<Token> {
-        if let Some(c) = self.chars.pop_front() {
-            if SYMBOLS.contains(&c) {
+    pub fn advance(&mut self) -> Option<(Token, Span)> {
+        loop {
+            let mut span = self.eof_span();
+            let c = self.bump()?;
+            let token = if SYMBOLS.contains(&c) {
                 match c {
                     // String constant
-                    '"' => self.get_string(),
+                    '"' => self.get_string(span),
                     // Symbols
                     _ => {
-                        if c == '/' && self.is_comment() {
-                            self.advance()
-                        } else {
-                            Some(Token::Symbol(c))
+                        if c == '/' && self.is_comment(span) {
+                            continue;
                         }
+                        Token::Symbol(c)
                     }
                 }
             // Integer constant
             } else if c.is_numeric() {
-                let mut num = String::from(c);
-                let mut end = self.chars.len();
-                for (i, &c) in self.chars.iter().enumerate() {
-                    if !c.is_numeric() {
-                        end = i;
-                        break;
-                    }
-                }
-                num.extend(self.chars.drain(..end));
+                let num = self.take_while(String::from(c), char::is_numeric);
                 if let Ok(i) = num.parse::<i16>() {
-                    Some(Token::IntConstant(i))
+                    Token::IntConstant(i)
                 } else {
-                    self.errors.push(CompilationError::InvalidInt);
-                    self.advance()
+                    span.end = self.offset;
+                    self.errors.push((CompilationError::InvalidInt(num), span));
+                    // Still a number as far as the parser is concerned,
+                    // otherwise the expression it's in would be reported as broken too
+                    Token::IntConstant(0)
                 }
             // Keywords and Identifiers
             } else if c.is_alphabetic() || c == '_' {
-                let mut word = String::from(c);
-                let mut end = self.chars.len();
-                for (i, &c) in self.chars.iter().enumerate() {
-                    if !(c.is_alphanumeric() || c == '_') {
-                        end = i;
-                        break;
-                    }
-                }
-                word.extend(self.chars.drain(..end));
+                let word = self.take_while(String::from(c), |c| c.is_alphanumeric() || c == '_');
                 if let Some(&k) = KEYWORDS.get(word.as_str()) {
-                    Some(Token::Keyword(k))
+                    Token::Keyword(k)
                 } else {
-                    Some(Token::Identifier(word))
+                    Token::Identifier(word)
                 }
             } else if !c.is_whitespace() {
-                self.errors.push(CompilationError::UnrecognizedToken);
-                self.advance()
+                span.end = self.offset;
+                self.errors
+                    .push((CompilationError::UnrecognizedToken(c), span));
+                continue;
             } else {
-                self.advance()
-            }
-        } else {
-            None
+                continue;
+            };
+            span.end = self.offset;
+            return Some((token, span));
         }
     }
 }
@@ -128,21 +186,21 @@ mod tests {
     #[test]
     fn test_keyword() {
         let mut tknzr = Tokenizer::new("class".chars().collect());
-        let token = tknzr.advance().expect("no token");
+        let (token, _) = tknzr.advance().expect("no token");
         assert_eq!(token, Keyword::Class);
     }
 
     #[test]
     fn test_symbol() {
         let mut tknzr = Tokenizer::new(String::from('('));
-        let token = tknzr.advance().expect("no token");
+        let (token, _) = tknzr.advance().expect("no token");
         assert_eq!(token, '(');
     }
 
     #[test]
     fn test_int() {
         let mut tknzr = Tokenizer::new("12364".chars().collect());
-        let token = tknzr.advance().expect("no token");
+        let (token, _) = tknzr.advance().expect("no token");
         assert_eq!(token, 12364);
     }
 
@@ -150,7 +208,7 @@ mod tests {
     fn test_identifier() {
         let s = "_helf12_3rd";
         let mut tknzr = Tokenizer::new(s.chars().collect());
-        let token = tknzr.advance().expect("no token");
+        let (token, _) = tknzr.advance().expect("no token");
         assert_eq!(token, Token::Identifier(String::from(s)));
     }
 
@@ -158,7 +216,7 @@ mod tests {
     fn test_string() {
         let s = "\"this is a string with a // comment in it and a /*/comment**/\"";
         let mut tknzr = Tokenizer::new(s.chars().collect());
-        let token = tknzr.advance().expect("no token");
+        let (token, _) = tknzr.advance().expect("no token");
         assert_eq!(
             token,
             String::from("this is a string with a // comment in it and a /*/comment**/")
@@ -169,7 +227,7 @@ mod tests {
     fn test_single_line_comment() {
         let s = "//Hello this is a comment\nvoid";
         let mut tknzr = Tokenizer::new(s.chars().collect());
-        let token = tknzr.advance().expect("no token");
+        let (token, _) = tknzr.advance().expect("no token");
         assert_eq!(token, Keyword::Void);
     }
 
@@ -177,7 +235,7 @@ mod tests {
     fn test_multi_line_comment() {
         let s = "/**Hello this is a comment\n\n\n**/let";
         let mut tknzr = Tokenizer::new(s.chars().collect());
-        let token = tknzr.advance().expect("no token");
+        let (token, _) = tknzr.advance().expect("no token");
         assert_eq!(token, Keyword::Let);
     }
     #[test]
@@ -185,10 +243,10 @@ mod tests {
         let s = "let do { } \"strings still work\" ;";
         let mut tknzr = Tokenizer::new(s.chars().collect());
         let mut tokens = vec![];
-        while let Some(t) = tknzr.advance() {
+        while let Some((t, _)) = tknzr.advance() {
             tokens.push(t);
         }
-        let t2 = vec![
+        let t2 = [
             Token::Keyword(Keyword::Let),
             Token::Keyword(Keyword::Do),
             Token::Symbol('{'),
@@ -200,4 +258,54 @@ mod tests {
             assert_eq!(&tokens[i], &t2[i]);
         }
     }
+
+    #[test]
+    fn test_spans() {
+        let s = "/* header */\nclass Main {\n  field int x;\n}";
+        let mut tknzr = Tokenizer::new(String::from(s));
+        let mut spans = vec![];
+        while let Some((_, span)) = tknzr.advance() {
+            spans.push(span);
+        }
+        assert_eq!(
+            spans[0],
+            Span {
+                start: 13,
+                end: 18,
+                line: 2,
+                col: 1
+            }
+        );
+        assert_eq!(
+            spans[5],
+            Span {
+                start: 38,
+                end: 39,
+                line: 3,
+                col: 13
+            }
+        );
+        assert_eq!(&s[spans[5].start..spans[5].end], "x");
+        assert_eq!(spans[7].line, 4);
+    }
+
+    #[test]
+    fn test_unterminated() {
+        let s = "let s = \"no end;\nlet x = 1; /* nor here\nlet y";
+        let mut tknzr = Tokenizer::new(String::from(s));
+        let mut tokens = vec![];
+        while let Some((t, _)) = tknzr.advance() {
+            tokens.push(t);
+        }
+        // Only the rest of the line is lost to the string, the comment takes everything
+        assert_eq!(tokens[3], String::from("no end;"));
+        assert_eq!(tokens[4], Keyword::Let);
+        assert_eq!(tokens.len(), 9);
+        let errors: Vec<_> = tknzr
+            .take_errors()
+            .into_iter()
+            .map(|(e, span)| (e.code(), &s[span.start..span.end], span.line))
+            .collect();
+        assert_eq!(errors, [("E0021", "\"", 1), ("E0022", "/*", 2)]);
+    }
 }
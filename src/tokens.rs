@@ -23,6 +23,17 @@ impl Token {
             _ => String::from("invalid type"),
         }
     }
+
+    // Short description for error messages, e.g. "identifier `x`"
+    pub fn describe(&self) -> String {
+        match self {
+            Token::Keyword(k) => format!("keyword `{k}`"),
+            Token::Symbol(c) => format!("symbol `{c}`"),
+            Token::Identifier(s) => format!("identifier `{s}`"),
+            Token::IntConstant(i) => format!("integer constant `{i}`"),
+            Token::StringConstant(s) => format!("string constant \"{s}\""),
+        }
+    }
 }
 
 impl Display for Token {
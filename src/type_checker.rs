@@ -0,0 +1,455 @@
+use std::fmt::Display;
+
+use crate::{
+    ast::*,
+    compilation_engine::CompilationError,
+    diagnostics::{Diagnostic, Level},
+    program_index::{ProgramIndex, Signature},
+    symbol_table::SymbolTable,
+    tokenizer::Span,
+    tokens::{Keyword, Token},
+};
+
+// How much of Jack's weak typing to put up with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strictness {
+    Off,
+    // Only mixing up objects with each other or with primitives, reported as warnings
+    // int, char and boolean are interchangeable and int can be used as an Array
+    Lenient,
+    // Booleans and numbers are kept apart as well, reported as errors
+    Strict,
+}
+
+impl Strictness {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(Strictness::Off),
+            "lenient" => Some(Strictness::Lenient),
+            "strict" => Some(Strictness::Strict),
+            _ => None,
+        }
+    }
+}
+
+// The type of an expression as far as it can be worked out
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Inferred {
+    Type(Type),
+    Null,
+    // Array elements, calls to other classes and anything else we can't see into
+    Unknown,
+}
+
+impl Display for Inferred {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Inferred::Type(t) => write!(f, "{t}"),
+            Inferred::Null => write!(f, "null"),
+            Inferred::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+fn is_array(t: &Type) -> bool {
+    *t == Type::Class(String::from("Array"))
+}
+
+fn is_primitive(t: &Type) -> bool {
+    matches!(t, Type::Int | Type::Char | Type::Boolean)
+}
+
+// Infers the type of every expression in a class and reports the ones used where they don't fit
+pub struct TypeChecker<'a> {
+    strictness: Strictness,
+    index: &'a ProgramIndex,
+    class_name: String,
+    symbol_table: SymbolTable,
+    subroutine: Option<Signature>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> TypeChecker<'a> {
+    pub fn new(strictness: Strictness, index: &'a ProgramIndex) -> Self {
+        TypeChecker {
+            strictness,
+            index,
+            class_name: String::new(),
+            symbol_table: SymbolTable::default(),
+            subroutine: None,
+            diagnostics: vec![],
+        }
+    }
+
+    pub fn check(mut self, class: &Class) -> Vec<Diagnostic> {
+        if self.strictness == Strictness::Off {
+            return vec![];
+        }
+        self.class_name = class.name.name.clone();
+        // Duplicates are reported by the CodeGenerator, the first declaration wins here
+        self.symbol_table = SymbolTable::for_class(class).0;
+        for subroutine in &class.subroutines {
+            self.check_subroutine(subroutine);
+        }
+        self.diagnostics
+    }
+
+    fn report(&mut self, error: CompilationError, span: Span, context: &'static str) {
+        let level = match self.strictness {
+            Strictness::Strict => Level::Error,
+            _ => Level::Warning,
+        };
+        self.diagnostics.push(Diagnostic {
+            level,
+            error,
+            span,
+            context: Some(context),
+        });
+    }
+
+    // Whether a value of type `found` can be stored somewhere declared as `expected`
+    fn compatible(&self, expected: &Type, found: &Inferred) -> bool {
+        let strict = self.strictness == Strictness::Strict;
+        let found = match found {
+            Inferred::Unknown => return true,
+            // null is 0, which is as good as false to a lenient checker
+            Inferred::Null => return !strict || !is_primitive(expected),
+            Inferred::Type(t) => t,
+        };
+        match (expected, found) {
+            _ if expected == found => true,
+            // There are no character literals, so chars are always built from ints
+            (Type::Int | Type::Char, Type::Int | Type::Char) => true,
+            (Type::Boolean, _) | (_, Type::Boolean) if !strict => {
+                is_primitive(expected) && is_primitive(found)
+            }
+            // Any object can be handed to something that takes an Array, e.g. Memory.deAlloc(this)
+            (e, Type::Class(_)) if is_array(e) => true,
+            // Arrays and ints are both just addresses
+            (e, Type::Int) | (Type::Int, e) if is_array(e) => !strict,
+            (Type::Class(_), f) if is_array(f) => !strict,
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, expected: &Type, found: &Inferred, span: Span, context: &'static str) {
+        if !self.compatible(expected, found) {
+            self.report(
+                CompilationError::TypeMismatch {
+                    expected: expected.to_string(),
+                    found: found.to_string(),
+                },
+                span,
+                context,
+            );
+        }
+    }
+
+    fn check_subroutine(&mut self, subroutine: &Subroutine) {
+        let _ = self
+            .symbol_table
+            .enter_subroutine(&self.class_name, subroutine);
+        self.subroutine = Some(Signature::of(subroutine));
+        self.check_statements(&subroutine.statements);
+    }
+
+    fn check_statements(&mut self, statements: &[Statement]) {
+        for statement in statements {
+            match statement {
+                Statement::Let {
+                    target,
+                    index,
+                    value,
+                    ..
+                } => {
+                    let value_type = self.infer_expression(value);
+                    if let Some(index) = index {
+                        self.check_index(target, index);
+                    } else if let Some(target_type) = self.var_type(&target.name) {
+                        self.expect(&target_type, &value_type, value.span(), "let statement");
+                    }
+                }
+                Statement::If {
+                    condition,
+                    then,
+                    otherwise,
+                    ..
+                } => {
+                    self.check_condition(condition, "if condition");
+                    self.check_statements(then);
+                    if let Some(otherwise) = otherwise {
+                        self.check_statements(otherwise);
+                    }
+                }
+                Statement::While {
+                    condition, body, ..
+                } => {
+                    self.check_condition(condition, "while condition");
+                    self.check_statements(body);
+                }
+                Statement::Do { call, .. } => {
+                    self.infer_call(call);
+                }
+                Statement::Return { value, .. } => {
+                    if let Some(value) = value {
+                        let value_type = self.infer_expression(value);
+                        let return_type =
+                            self.subroutine.as_ref().and_then(|s| s.return_type.clone());
+                        if let Some(return_type) = return_type {
+                            self.expect(
+                                &return_type,
+                                &value_type,
+                                value.span(),
+                                "return statement",
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_condition(&mut self, condition: &Expr, context: &'static str) {
+        let found = self.infer_expression(condition);
+        self.expect(&Type::Boolean, &found, condition.span(), context);
+    }
+
+    fn check_index(&mut self, array: &Ident, index: &Expr) {
+        let found = self.infer_expression(index);
+        self.expect(&Type::Int, &found, index.span(), "array index");
+        if self.strictness == Strictness::Strict {
+            if let Some(array_type) = self.var_type(&array.name) {
+                if !is_array(&array_type) {
+                    self.report(
+                        CompilationError::TypeMismatch {
+                            expected: String::from("Array"),
+                            found: array_type.to_string(),
+                        },
+                        array.span,
+                        "array access",
+                    );
+                }
+            }
+        }
+    }
+
+    fn var_type(&self, name: &str) -> Option<Type> {
+        self.symbol_table
+            .get(name)
+            .map(|entry| Type::from_name(entry.get_type()))
+    }
+
+    fn infer_expression(&mut self, expr: &Expr) -> Inferred {
+        match expr {
+            Expr::Term(term) => self.infer_term(term),
+            Expr::Binary { op, lhs, rhs, .. } => {
+                let lhs_type = self.infer_expression(lhs);
+                let rhs_type = self.infer_expression(rhs);
+                match op {
+                    BinaryOp::Add
+                    | BinaryOp::Sub
+                    | BinaryOp::Mul
+                    | BinaryOp::Div
+                    | BinaryOp::Mod => {
+                        self.expect_number(&lhs_type, lhs.span(), "arithmetic");
+                        self.expect_number(&rhs_type, rhs.span(), "arithmetic");
+                        Inferred::Type(Type::Int)
+                    }
+                    BinaryOp::Lt | BinaryOp::Gt => {
+                        self.expect_number(&lhs_type, lhs.span(), "comparison");
+                        self.expect_number(&rhs_type, rhs.span(), "comparison");
+                        Inferred::Type(Type::Boolean)
+                    }
+                    BinaryOp::Eq => {
+                        if let Inferred::Type(t) = &lhs_type {
+                            self.expect(t, &rhs_type, rhs.span(), "comparison");
+                        }
+                        Inferred::Type(Type::Boolean)
+                    }
+                    // Bitwise on numbers and logical on booleans, but not a mix of both
+                    BinaryOp::And | BinaryOp::Or => match lhs_type {
+                        Inferred::Type(Type::Boolean) => {
+                            self.expect(&Type::Boolean, &rhs_type, rhs.span(), "logical operand");
+                            lhs_type
+                        }
+                        _ => {
+                            self.expect_number(&lhs_type, lhs.span(), "logical operand");
+                            self.expect_number(&rhs_type, rhs.span(), "logical operand");
+                            Inferred::Type(Type::Int)
+                        }
+                    },
+                }
+            }
+        }
+    }
+
+    fn expect_number(&mut self, found: &Inferred, span: Span, context: &'static str) {
+        self.expect(&Type::Int, found, span, context);
+    }
+
+    fn infer_term(&mut self, term: &Term) -> Inferred {
+        match term {
+            Term::Constant(token, _) => match token {
+                Token::IntConstant(_) => Inferred::Type(Type::Int),
+                Token::StringConstant(_) => Inferred::Type(Type::Class(String::from("String"))),
+                Token::Keyword(Keyword::True | Keyword::False) => Inferred::Type(Type::Boolean),
+                Token::Keyword(Keyword::Null) => Inferred::Null,
+                Token::Keyword(Keyword::This) => match &self.subroutine {
+                    Some(s) if s.kind != SubroutineKind::Function => {
+                        Inferred::Type(Type::Class(self.class_name.clone()))
+                    }
+                    _ => Inferred::Unknown,
+                },
+                _ => Inferred::Unknown,
+            },
+            Term::Var(name) => self
+                .var_type(&name.name)
+                .map_or(Inferred::Unknown, Inferred::Type),
+            Term::Index(name, index, _) => {
+                self.check_index(name, index);
+                Inferred::Unknown
+            }
+            Term::Call(call) => self.infer_call(call),
+            Term::Paren(expr, _) => self.infer_expression(expr),
+            Term::Unary(op, term, _) => {
+                let found = self.infer_term(term);
+                match op {
+                    UnaryOp::Neg => {
+                        self.expect_number(&found, term.span(), "arithmetic");
+                        Inferred::Type(Type::Int)
+                    }
+                    UnaryOp::Not if found == Inferred::Type(Type::Boolean) => found,
+                    UnaryOp::Not => {
+                        self.expect_number(&found, term.span(), "logical operand");
+                        Inferred::Type(Type::Int)
+                    }
+                }
+            }
+        }
+    }
+
+    fn infer_call(&mut self, call: &SubroutineCall) -> Inferred {
+        let class_name = match &call.receiver {
+            None => self.class_name.clone(),
+            Some(receiver) => match self.var_type(&receiver.name) {
+                Some(Type::Class(class_name)) => class_name,
+                Some(t) => {
+                    self.report(
+                        CompilationError::MethodOnPrimitive {
+                            name: receiver.name.clone(),
+                            var_type: t.to_string(),
+                        },
+                        receiver.span,
+                        "subroutine call",
+                    );
+                    String::new()
+                }
+                // Not a variable, so it's a function or constructor of another class
+                None => receiver.name.clone(),
+            },
+        };
+        let signature = self.index.get(&class_name, &call.name.name).cloned();
+
+        let args: Vec<Inferred> = call
+            .args
+            .iter()
+            .map(|arg| self.infer_expression(arg))
+            .collect();
+        match signature {
+            Some(signature) => {
+                if signature.params.len() == args.len() {
+                    for ((param, found), arg) in signature.params.iter().zip(&args).zip(&call.args)
+                    {
+                        self.expect(param, found, arg.span(), "argument");
+                    }
+                }
+                signature
+                    .return_type
+                    .map_or(Inferred::Unknown, Inferred::Type)
+            }
+            None => Inferred::Unknown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CompilationEngine;
+
+    // Only what the TypeChecker reports, not unused variables and the like
+    fn check(strictness: Strictness, source: &str) -> Vec<Diagnostic> {
+        let mut engine = CompilationEngine::new().with_type_check(strictness);
+        match engine.compile_str(source) {
+            Ok(program) => program.warnings,
+            Err(errors) => errors,
+        }
+        .into_iter()
+        .filter(|d| {
+            matches!(
+                d.error,
+                CompilationError::TypeMismatch { .. } | CompilationError::MethodOnPrimitive { .. }
+            )
+        })
+        .collect()
+    }
+
+    #[test]
+    fn test_lenient_mismatches() {
+        let source = "class Main { field int n; \
+                      method int f(String s, Array a, char c, boolean b) { \
+                      let n = s; let n = c + b; let a = 0; let a = this; \
+                      if (s) { do n.g(); } \
+                      return \"x\"; } }";
+        let found: Vec<_> = check(Strictness::Lenient, source)
+            .iter()
+            .map(|d| {
+                (
+                    d.level,
+                    &source[d.span.start..d.span.end],
+                    d.error.to_string(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (
+                    Level::Warning,
+                    "s",
+                    String::from("mismatched types: expected `int`, found `String`")
+                ),
+                (
+                    Level::Warning,
+                    "s",
+                    String::from("mismatched types: expected `boolean`, found `String`")
+                ),
+                (
+                    Level::Warning,
+                    "n",
+                    String::from("`n` is an `int`, which has no methods")
+                ),
+                (
+                    Level::Warning,
+                    "\"x\"",
+                    String::from("mismatched types: expected `int`, found `String`")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_strict_mismatches() {
+        let source = "class Main { function void f(int x, boolean b, Array a) { \
+                      var Main m; let m = null; let x = null; let a = x; \
+                      while (x) { let x = x + b; } let b = ~x; return; } }";
+        let errors = check(Strictness::Strict, source);
+        assert!(errors.iter().all(|d| d.level == Level::Error));
+        let snippets: Vec<_> = errors
+            .iter()
+            .map(|d| &source[d.span.start..d.span.end])
+            .collect();
+        assert_eq!(snippets, ["null", "x", "x", "b", "~x"]);
+        assert!(check(Strictness::Off, source).is_empty());
+    }
+}
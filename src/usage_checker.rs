@@ -0,0 +1,203 @@
+use crate::{
+    ast::*,
+    compilation_engine::CompilationError,
+    diagnostics::{Diagnostic, Level},
+    symbol_table::{Kind, SymbolTable},
+    tokenizer::Span,
+};
+
+// Keeps count of how every variable in a class is used and warns about the suspicious ones
+#[derive(Default)]
+pub struct UsageChecker {
+    symbol_table: SymbolTable,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl UsageChecker {
+    pub fn new() -> Self {
+        UsageChecker::default()
+    }
+
+    pub fn check(mut self, class: &Class) -> Vec<Diagnostic> {
+        // Duplicates are reported by the CodeGenerator, the first declaration wins here
+        self.symbol_table = SymbolTable::for_class(class).0;
+        for subroutine in &class.subroutines {
+            self.check_subroutine(&class.name.name, subroutine);
+        }
+        // Fields are private to the class, so if nothing here reads one nothing can
+        self.report_unused(Kind::Field);
+        self.diagnostics
+    }
+
+    fn warn(&mut self, error: CompilationError, span: Span) {
+        self.diagnostics.push(Diagnostic {
+            level: Level::Warning,
+            error,
+            span,
+            context: None,
+        });
+    }
+
+    fn report_unused(&mut self, kind: Kind) {
+        let mut unused: Vec<(String, Span)> = self
+            .symbol_table
+            .symbols(kind)
+            // A method's `this` is only ever used implicitly
+            .filter(|(name, entry)| entry.get_reads() == 0 && *name != "this")
+            .map(|(name, entry)| (name.clone(), entry.get_span()))
+            .collect();
+        unused.sort_by_key(|(_, span)| span.start);
+        for (name, span) in unused {
+            self.warn(CompilationError::UnusedVariable { name, kind }, span);
+        }
+    }
+
+    fn check_subroutine(&mut self, class_name: &str, subroutine: &Subroutine) {
+        let _ = self.symbol_table.enter_subroutine(class_name, subroutine);
+        for name in subroutine.vars.iter().flat_map(|dec| &dec.names) {
+            if let Some(outer) = self.symbol_table.get_class_level(&name.name) {
+                let kind = outer.get_kind();
+                self.warn(
+                    CompilationError::ShadowedVariable {
+                        name: name.name.clone(),
+                        kind,
+                    },
+                    name.span,
+                );
+            }
+        }
+        self.check_statements(&subroutine.statements);
+        self.report_unused(Kind::Arg);
+        self.report_unused(Kind::Var);
+    }
+
+    fn read(&mut self, name: &Ident) {
+        // Undeclared variables are reported by the CodeGenerator
+        let Some(entry) = self.symbol_table.record_read(&name.name) else {
+            return;
+        };
+        // Only the first read, the rest would just be noise
+        if entry.get_kind() == Kind::Var && entry.get_writes() == 0 && entry.get_reads() == 1 {
+            self.warn(
+                CompilationError::UninitializedVariable(name.name.clone()),
+                name.span,
+            );
+        }
+    }
+
+    // Statements are taken in the order they're written, so a read inside a loop
+    // of a variable that's only assigned further down still counts as uninitialized
+    fn check_statements(&mut self, statements: &[Statement]) {
+        for statement in statements {
+            match statement {
+                Statement::Let {
+                    target,
+                    index,
+                    value,
+                    ..
+                } => {
+                    if let Some(index) = index {
+                        self.check_expression(index);
+                        // Storing into an array goes through the pointer, it doesn't change it
+                        self.read(target);
+                        self.check_expression(value);
+                    } else {
+                        self.check_expression(value);
+                        self.symbol_table.record_write(&target.name);
+                    }
+                }
+                Statement::If {
+                    condition,
+                    then,
+                    otherwise,
+                    ..
+                } => {
+                    self.check_expression(condition);
+                    self.check_statements(then);
+                    if let Some(otherwise) = otherwise {
+                        self.check_statements(otherwise);
+                    }
+                }
+                Statement::While {
+                    condition, body, ..
+                } => {
+                    self.check_expression(condition);
+                    self.check_statements(body);
+                }
+                Statement::Do { call, .. } => self.check_call(call),
+                Statement::Return { value, .. } => {
+                    if let Some(value) = value {
+                        self.check_expression(value);
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_call(&mut self, call: &SubroutineCall) {
+        if let Some(receiver) = &call.receiver {
+            self.read(receiver);
+        }
+        for arg in &call.args {
+            self.check_expression(arg);
+        }
+    }
+
+    fn check_expression(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Term(term) => self.check_term(term),
+            Expr::Binary { lhs, rhs, .. } => {
+                self.check_expression(lhs);
+                self.check_expression(rhs);
+            }
+        }
+    }
+
+    fn check_term(&mut self, term: &Term) {
+        match term {
+            Term::Constant(..) => {}
+            Term::Var(name) => self.read(name),
+            Term::Index(name, index, _) => {
+                self.check_expression(index);
+                self.read(name);
+            }
+            Term::Call(call) => self.check_call(call),
+            Term::Paren(expr, _) => self.check_expression(expr),
+            Term::Unary(_, term, _) => self.check_term(term),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{CompilationEngine, Strictness};
+
+    #[test]
+    fn test_usage_warnings() {
+        let source = "class Main { field int x, y; static int count; \
+                      constructor Main new(int ax, int unused) { let x = ax; let count = x; return this; } \
+                      method int f() { var int x, a, b; var Array c; \
+                      while (a < 10) { let a = a + 1; } \
+                      let c[0] = 1; let x = 2; return b; } }";
+        let warnings: Vec<_> = CompilationEngine::new()
+            .with_type_check(Strictness::Off)
+            .compile_str(source)
+            .unwrap()
+            .warnings
+            .into_iter()
+            .map(|d| d.error.to_string())
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                "field `y` is never read",
+                "argument `unused` is never read",
+                "local variable `x` shadows a field of the same name",
+                "local variable `x` is never read",
+                "`a` is read before anything is assigned to it",
+                "`c` is read before anything is assigned to it",
+                "`b` is read before anything is assigned to it",
+            ]
+        );
+    }
+}
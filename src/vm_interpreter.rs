@@ -0,0 +1,630 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io::{Read, Write},
+    rc::Rc,
+};
+
+use crate::{
+    hack::{KBD, RAM_SIZE, SCREEN},
+    native_os::{self, NativeOs},
+    vm_writer::{Comparison, MemSegment, VmCommand},
+};
+
+// Where the stack starts and where it runs into the heap
+const STACK_START: i16 = 256;
+const STACK_END: i16 = 2048;
+// Statics of every class share the addresses between R15 and the stack
+const FIRST_STATIC: i16 = 16;
+
+const SP: usize = 0;
+const LCL: usize = 1;
+const ARG: usize = 2;
+const THIS: usize = 3;
+const THAT: usize = 4;
+const TEMP: i16 = 5;
+
+// What went wrong and which command was running at the time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeError {
+    pub message: String,
+    pub location: Option<Location>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub class: String,
+    // Counting from 1, the same as the line in a .vm file written by the compiler
+    pub command_number: usize,
+    pub command: String,
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.location {
+            Some(at) => write!(
+                f,
+                "{}.vm command {} `{}`: {}",
+                at.class, at.command_number, at.command, self.message
+            ),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+struct Instruction {
+    class: Rc<str>,
+    // Position within the class, for reporting errors
+    index: usize,
+    command: VmCommand,
+    // Where a goto or if-goto lands, resolved when the class is loaded
+    target: Option<usize>,
+}
+
+// A function that has been called but hasn't returned yet
+struct Frame {
+    function: String,
+    // None for the entry point, returning from it goes back to the caller of `call`
+    return_to: Option<usize>,
+    args: i16,
+    // Known once the function command has run
+    locals: Option<i16>,
+}
+
+// Runs VM code directly, keeping the stack, segments and heap in 32K words of RAM
+// laid out the same way as on the Hack computer
+pub struct VmInterpreter {
+    ram: Vec<i16>,
+    code: Vec<Instruction>,
+    functions: HashMap<String, usize>,
+    statics: HashMap<Rc<str>, i16>,
+    next_static: i16,
+    frames: Vec<Frame>,
+    pc: usize,
+    steps: u64,
+    max_steps: Option<u64>,
+    // Set when the entry point returns
+    result: Option<i16>,
+    // Any OS function not loaded as VM code runs natively instead
+    pub(crate) os: NativeOs,
+    halted: bool,
+    // A native function for the next step to call, see start
+    pending: Option<String>,
+}
+
+impl Default for VmInterpreter {
+    fn default() -> Self {
+        VmInterpreter::new()
+    }
+}
+
+impl VmInterpreter {
+    pub fn new() -> Self {
+        let mut ram = vec![0; RAM_SIZE];
+        ram[SP] = STACK_START;
+        VmInterpreter {
+            ram,
+            code: vec![],
+            functions: HashMap::new(),
+            statics: HashMap::new(),
+            next_static: FIRST_STATIC,
+            frames: vec![],
+            pc: 0,
+            steps: 0,
+            max_steps: None,
+            result: None,
+            os: NativeOs::new(),
+            halted: false,
+            pending: None,
+        }
+    }
+
+    // Gives up with an error after this many commands, for programs that never halt
+    pub fn with_max_steps(mut self, max_steps: u64) -> Self {
+        self.max_steps = Some(max_steps);
+        self
+    }
+
+    // Where the OS prints to, stdout by default
+    pub fn with_output(mut self, output: impl Write + 'static) -> Self {
+        self.os.output = Box::new(output);
+        self
+    }
+
+    // Where the OS reads key presses from, one byte per key, nothing by default
+    pub fn with_keyboard(mut self, keyboard: impl Read + 'static) -> Self {
+        self.os.keyboard = Box::new(keyboard);
+        self
+    }
+
+    pub fn has_function(&self, function: &str) -> bool {
+        self.functions.contains_key(function)
+    }
+
+    pub fn ram(&self) -> &[i16] {
+        &self.ram
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [i16] {
+        &mut self.ram
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    // The 512x256 screen, 32 words to a row with the leftmost pixel in the lowest bit
+    pub fn screen(&self) -> &[i16] {
+        &self.ram[SCREEN..KBD]
+    }
+
+    // Whether Sys.halt or Sys.error has stopped the program
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    pub(crate) fn halt(&mut self) {
+        self.halted = true;
+    }
+
+    // Adds the code of one class, a class loaded again replaces its functions
+    pub fn load(&mut self, class_name: &str, commands: &[VmCommand]) -> Result<(), RuntimeError> {
+        let class: Rc<str> = Rc::from(class_name);
+        let start = self.code.len();
+        let mut labels = HashMap::new();
+        let mut function = "";
+        let mut statics = 0;
+        for (index, command) in commands.iter().enumerate() {
+            let error = |message: String| RuntimeError {
+                message,
+                location: Some(Location {
+                    class: class_name.to_string(),
+                    command_number: index + 1,
+                    command: command.to_string(),
+                }),
+            };
+            match command {
+                VmCommand::Function(name, _) => {
+                    function = name;
+                    self.functions.insert(name.clone(), start + index);
+                }
+                VmCommand::Label(label)
+                    if labels.insert((function, label), start + index).is_some() =>
+                {
+                    return Err(error(format!("`{label}` is already defined in {function}")));
+                }
+                VmCommand::Push(MemSegment::Static, i) | VmCommand::Pop(MemSegment::Static, i) => {
+                    statics = statics.max(i + 1)
+                }
+                VmCommand::Push(MemSegment::Temp, i) | VmCommand::Pop(MemSegment::Temp, i)
+                    if *i > 7 =>
+                {
+                    return Err(error(String::from("temp only goes up to 7")))
+                }
+                VmCommand::Push(MemSegment::Pointer, i)
+                | VmCommand::Pop(MemSegment::Pointer, i)
+                    if *i > 1 =>
+                {
+                    return Err(error(String::from("pointer only goes up to 1")))
+                }
+                VmCommand::Pop(MemSegment::Constant, _) => {
+                    return Err(error(String::from("can't pop into the constant segment")))
+                }
+                _ => {}
+            }
+        }
+        if self.next_static + statics > STACK_START {
+            return Err(RuntimeError {
+                message: format!("there's no room left for the statics of {class_name}"),
+                location: None,
+            });
+        }
+        self.statics.insert(class.clone(), self.next_static);
+        self.next_static += statics;
+
+        // Labels only need to be found within the function they're declared in
+        function = "";
+        for (index, command) in commands.iter().enumerate() {
+            let target = match command {
+                VmCommand::Function(name, _) => {
+                    function = name;
+                    None
+                }
+                VmCommand::Goto(label) | VmCommand::IfGoto(label) => {
+                    labels.get(&(function, label)).copied()
+                }
+                _ => None,
+            };
+            self.code.push(Instruction {
+                class: class.clone(),
+                index,
+                command: command.clone(),
+                target,
+            });
+        }
+        Ok(())
+    }
+
+    // Starts the program the way the Hack computer would, Sys.init then runs Main.main
+    pub fn run(&mut self) -> Result<(), RuntimeError> {
+        self.call("Sys.init", &[]).map(|_| ())
+    }
+
+    // Calls a function such as `Main.main` and runs until it returns with its value,
+    // or until the program halts in which case the value is 0
+    pub fn call(&mut self, function: &str, args: &[i16]) -> Result<i16, RuntimeError> {
+        if !self.functions.contains_key(function) && native_os::lookup(function).is_none() {
+            return Err(RuntimeError {
+                message: format!("there is no function named `{function}`"),
+                location: None,
+            });
+        }
+        // Whatever was running before is abandoned, e.g. after an error
+        self.frames.clear();
+        self.halted = false;
+        self.invoke(function, args)
+    }
+
+    // Points the next step at a function without calling it, the way the VM emulator
+    // starts a program, so nothing needs to be on the stack for it to return to
+    pub fn start(&mut self, function: &str) -> Result<(), RuntimeError> {
+        self.frames.clear();
+        self.halted = false;
+        match self.functions.get(function) {
+            Some(&start) => self.pc = start,
+            None if native_os::lookup(function).is_some() => {
+                self.pending = Some(function.to_string())
+            }
+            None => {
+                return Err(RuntimeError {
+                    message: format!("there is no function named `{function}`"),
+                    location: None,
+                })
+            }
+        }
+        Ok(())
+    }
+
+    // Calls a function from within another one, for the native OS to call the rest of the OS
+    pub(crate) fn invoke(&mut self, function: &str, args: &[i16]) -> Result<i16, RuntimeError> {
+        if !self.functions.contains_key(function) {
+            return match native_os::lookup(function) {
+                Some((_, native)) => native(self, args),
+                None => Err(self.error(format!("there is no function named `{function}`"))),
+            };
+        }
+        let (pc, depth) = (self.pc, self.frames.len());
+        self.result = None;
+        for arg in args {
+            self.push(*arg)?;
+        }
+        self.enter(function, args.len() as i16, None)?;
+        // Anything called in between takes its own result before returning
+        while self.frames.len() > depth || self.result.is_none() {
+            if self.halted {
+                return Ok(0);
+            }
+            if !self.step()? {
+                return Err(self.error(format!("{function} ran past the end of the program")));
+            }
+        }
+        self.pc = pc;
+        Ok(self.result.take().unwrap_or_default())
+    }
+
+    // Runs a single command, false if there's nothing left to run
+    pub fn step(&mut self) -> Result<bool, RuntimeError> {
+        if let Some(function) = self.pending.take() {
+            self.steps += 1;
+            return self.invoke(&function, &[]).map(|_| true);
+        }
+        // Labels do nothing when they run, so they don't take a step of their own
+        while let Some(VmCommand::Label(_)) = self.code.get(self.pc).map(|i| &i.command) {
+            self.pc += 1;
+        }
+        if self.pc >= self.code.len() || self.halted {
+            return Ok(false);
+        }
+        if self.max_steps.is_some_and(|max| self.steps >= max) {
+            return Err(self.error(format!("gave up after {} steps", self.steps)));
+        }
+        self.steps += 1;
+        let pc = self.pc;
+        self.pc += 1;
+        let command = &self.code[pc].command;
+        match command {
+            VmCommand::Add => self.binary(|x, y| x.wrapping_add(y))?,
+            VmCommand::Sub => self.binary(|x, y| x.wrapping_sub(y))?,
+            VmCommand::And => self.binary(|x, y| x & y)?,
+            VmCommand::Or => self.binary(|x, y| x | y)?,
+            VmCommand::Compare(cmp) => {
+                let cmp = *cmp;
+                self.binary(|x, y| {
+                    let result = match cmp {
+                        Comparison::Eq => x == y,
+                        Comparison::GT => x > y,
+                        Comparison::LT => x < y,
+                    };
+                    -(result as i16)
+                })?
+            }
+            VmCommand::Neg => {
+                let x = self.pop()?;
+                self.push(x.wrapping_neg())?;
+            }
+            VmCommand::Not => {
+                let x = self.pop()?;
+                self.push(!x)?;
+            }
+            VmCommand::Push(segment, index) => {
+                let (segment, index) = (*segment, *index);
+                let value = match segment {
+                    MemSegment::Constant => index,
+                    _ => self.ram[self.address(segment, index)?],
+                };
+                self.push(value)?;
+            }
+            VmCommand::Pop(segment, index) => {
+                let address = self.address(*segment, *index)?;
+                self.ram[address] = self.pop()?;
+            }
+            VmCommand::Label(_) => {}
+            VmCommand::Goto(label) => self.pc = self.target(pc, label)?,
+            VmCommand::IfGoto(label) => {
+                let label = label.clone();
+                if self.pop()? != 0 {
+                    self.pc = self.target(pc, &label)?;
+                }
+            }
+            VmCommand::Function(_, locals) => {
+                let locals = *locals;
+                for _ in 0..locals {
+                    self.push(0)?;
+                }
+                if let Some(frame) = self.frames.last_mut() {
+                    frame.locals = Some(locals);
+                }
+            }
+            VmCommand::Call(name, args) => {
+                let (name, args) = (name.clone(), *args);
+                self.enter(&name, args, Some(self.pc))?
+            }
+            VmCommand::Return => self.leave()?,
+        }
+        Ok(true)
+    }
+
+    pub(crate) fn error(&self, message: String) -> RuntimeError {
+        // The command that was running, not the next one
+        let location = self.code.get(self.pc.wrapping_sub(1)).map(|i| Location {
+            class: i.class.to_string(),
+            command_number: i.index + 1,
+            command: i.command.to_string(),
+        });
+        RuntimeError { message, location }
+    }
+
+    fn target(&self, pc: usize, label: &str) -> Result<usize, RuntimeError> {
+        self.code[pc].target.ok_or_else(|| {
+            let function = self.frames.last().map_or("", |f| &f.function);
+            self.error(format!("there is no label `{label}` in {function}"))
+        })
+    }
+
+    fn push(&mut self, value: i16) -> Result<(), RuntimeError> {
+        let sp = self.ram[SP];
+        if !(0..STACK_END).contains(&sp) {
+            return Err(self.error(String::from("stack overflow")));
+        }
+        self.ram[sp as usize] = value;
+        self.ram[SP] = sp + 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<i16, RuntimeError> {
+        // Only what the current function pushed itself can be popped
+        let bottom = match self.frames.last() {
+            Some(frame) => self.ram[LCL].saturating_add(frame.locals.unwrap_or(0)),
+            None => STACK_START,
+        };
+        let sp = self.ram[SP];
+        if sp <= bottom {
+            return Err(self.error(String::from("stack underflow")));
+        }
+        self.ram[SP] = sp - 1;
+        Ok(self.ram[sp as usize - 1])
+    }
+
+    fn binary(&mut self, op: impl Fn(i16, i16) -> i16) -> Result<(), RuntimeError> {
+        let y = self.pop()?;
+        let x = self.pop()?;
+        self.push(op(x, y))
+    }
+
+    // The RAM address of a segment entry, checked against the size of the segment where it's known
+    fn address(&self, segment: MemSegment, index: i16) -> Result<usize, RuntimeError> {
+        let frame = self.frames.last();
+        let limit = match segment {
+            MemSegment::Local => frame.and_then(|f| f.locals),
+            MemSegment::Argument => frame.map(|f| f.args),
+            _ => None,
+        };
+        if let Some(limit) = limit.filter(|limit| index >= *limit) {
+            let function = frame.map_or("", |f| &f.function);
+            return Err(self.error(format!(
+                "{segment} {index} is out of bounds, {function} only has {limit}"
+            )));
+        }
+        let address = match segment {
+            MemSegment::Local => self.ram[LCL] as i32 + index as i32,
+            MemSegment::Argument => self.ram[ARG] as i32 + index as i32,
+            MemSegment::This => self.ram[THIS] as i32 + index as i32,
+            MemSegment::That => self.ram[THAT] as i32 + index as i32,
+            MemSegment::Pointer => THIS as i32 + index as i32,
+            MemSegment::Temp => (TEMP + index) as i32,
+            MemSegment::Static => {
+                let class = &self.code[self.pc - 1].class;
+                (self.statics[class] + index) as i32
+            }
+            MemSegment::Constant => {
+                return Err(self.error(String::from("constant has no address")));
+            }
+        };
+        if !(0..RAM_SIZE as i32).contains(&address) {
+            return Err(self.error(format!("{segment} {index} is at {address}, outside of RAM")));
+        }
+        Ok(address as usize)
+    }
+
+    // Saves the caller's frame and jumps to the function, the arguments already pushed
+    fn enter(
+        &mut self,
+        function: &str,
+        args: i16,
+        return_to: Option<usize>,
+    ) -> Result<(), RuntimeError> {
+        let Some(&start) = self.functions.get(function) else {
+            let Some((arity, native)) = native_os::lookup(function) else {
+                return Err(self.error(format!("there is no function named `{function}`")));
+            };
+            if args as usize != arity {
+                return Err(self.error(format!(
+                    "{function} takes {arity} arguments but {args} were given"
+                )));
+            }
+            // Takes a single step, however much it does
+            let mut values = vec![0; args as usize];
+            for value in values.iter_mut().rev() {
+                *value = self.pop()?;
+            }
+            let value = native(self, &values)?;
+            if !self.halted {
+                self.push(value)?;
+            }
+            return Ok(());
+        };
+        let sp = self.ram[SP];
+        // Everything is kept in RAM as well so programs see the same memory as on the real thing
+        self.push(return_to.unwrap_or(0) as i16)?;
+        for pointer in [LCL, ARG, THIS, THAT] {
+            self.push(self.ram[pointer])?;
+        }
+        self.ram[ARG] = sp - args;
+        self.ram[LCL] = self.ram[SP];
+        self.frames.push(Frame {
+            function: function.to_string(),
+            return_to,
+            args,
+            locals: None,
+        });
+        self.pc = start;
+        Ok(())
+    }
+
+    fn leave(&mut self) -> Result<(), RuntimeError> {
+        let value = self.pop()?;
+        let frame = self.ram[LCL] as usize;
+        if frame < 5 {
+            return Err(self.error(String::from("returned without a frame to return to")));
+        }
+        let arg = self.ram[ARG];
+        if !(0..STACK_END).contains(&arg) {
+            return Err(self.error(String::from("argument pointer is outside of the stack")));
+        }
+        self.ram[arg as usize] = value;
+        self.ram[SP] = arg + 1;
+        for (i, pointer) in [THAT, THIS, ARG, LCL].into_iter().enumerate() {
+            self.ram[pointer] = self.ram[frame - 1 - i];
+        }
+        match self.frames.pop() {
+            Some(Frame {
+                return_to: Some(pc),
+                ..
+            }) => self.pc = pc,
+            // Back to whoever called the entry point, which takes the value off the stack
+            Some(_) => {
+                self.ram[SP] = arg;
+                self.result = Some(value);
+            }
+            // Nothing called this function, as when running a lone function from a test script
+            None => self.pc = self.code.len(),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile_str;
+
+    fn interpreter(sources: &[&str]) -> VmInterpreter {
+        let mut vm = VmInterpreter::new().with_max_steps(10_000);
+        for source in sources {
+            let program = compile_str(source).unwrap();
+            vm.load(&program.class_name, &program.commands).unwrap();
+        }
+        vm
+    }
+
+    #[test]
+    fn test_call_entry_point() {
+        let mut vm = interpreter(&[
+            "class Main { static int calls; \
+             function int fib(int n) { let calls = calls + 1; \
+             if (n < 2) { return n; } return Main.fib(n - 1) + Main.fib(n - 2); } \
+             function int count() { return calls; } }",
+            "class Counter { field int n; constructor Counter new() { let n = 5; return this; } \
+             method int next() { let n = n + 1; return n; } \
+             function int run() { var Counter c; var int x; let c = Counter.new(); \
+             let x = c.next(); return c.next() + x; } }",
+        ]);
+        assert_eq!(vm.call("Main.fib", &[10]), Ok(55));
+        assert_eq!(vm.call("Main.count", &[]), Ok(177));
+        assert_eq!(vm.ram()[SP], STACK_START);
+        // Memory.alloc comes from the native OS
+        assert_eq!(vm.call("Counter.run", &[]), Ok(13));
+        assert_eq!(
+            vm.call("Counter.prev", &[]).unwrap_err().to_string(),
+            "there is no function named `Counter.prev`"
+        );
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let commands = |source: &str| crate::vm_writer::parse_vm(source).unwrap();
+        let mut vm = VmInterpreter::new();
+        vm.load(
+            "Bad",
+            &commands(
+                "function Bad.underflow 0\nadd\nreturn\n\
+                 function Bad.local 1\npush local 1\nreturn\n\
+                 function Bad.loop 0\nlabel L\ngoto L\n\
+                 function Bad.that 0\npush constant 32767\npop pointer 1\npush that 1\nreturn",
+            ),
+        )
+        .unwrap();
+        let errors: Vec<_> = ["Bad.underflow", "Bad.local", "Bad.that"]
+            .into_iter()
+            .map(|f| vm.call(f, &[]).unwrap_err().to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "Bad.vm command 2 `add`: stack underflow",
+                "Bad.vm command 5 `push local 1`: local 1 is out of bounds, Bad.local only has 1",
+                "Bad.vm command 13 `push that 1`: that 1 is at 32768, outside of RAM",
+            ]
+        );
+
+        let mut vm = VmInterpreter::new().with_max_steps(100);
+        vm.load("Bad", &commands("function Bad.loop 0\nlabel L\ngoto L"))
+            .unwrap();
+        assert_eq!(
+            vm.call("Bad.loop", &[]).unwrap_err().message,
+            "gave up after 100 steps"
+        );
+    }
+}
@@ -0,0 +1,298 @@
+use std::{collections::BTreeSet, fmt::Write};
+
+use crate::vm_writer::{Comparison, MemSegment, VmCommand};
+
+// Lowers VM code to Hack assembly, one class at a time, into a single program
+// Calls, returns and comparisons jump to shared routines at the end instead of
+// being written out in full each time, otherwise the OS alone wouldn't fit in ROM
+#[derive(Debug, Default)]
+pub struct VmTranslator {
+    asm: String,
+    // Statics are named after the class, labels after the function they're in
+    class_name: String,
+    function: String,
+    // For making return addresses unique
+    counter: usize,
+    has_sys_init: bool,
+    uses_call: bool,
+    uses_return: bool,
+    comparisons: Vec<Comparison>,
+    // Every function there's code for and every one that's called, to find the missing ones
+    defined: BTreeSet<String>,
+    called: BTreeSet<String>,
+}
+
+impl VmTranslator {
+    pub fn new() -> Self {
+        VmTranslator::default()
+    }
+
+    pub fn translate(&mut self, class_name: &str, commands: &[VmCommand]) {
+        self.class_name = class_name.to_string();
+        self.function.clear();
+        for command in commands {
+            let _ = writeln!(self.asm, "// {command}");
+            self.translate_command(command);
+        }
+    }
+
+    // Functions that are called but that no translated class defines, in order of name
+    pub fn undefined(&self) -> Vec<String> {
+        self.called.difference(&self.defined).cloned().collect()
+    }
+
+    // The complete program, with the bootstrap code in front if there's a Sys.init to call
+    pub fn finish(mut self) -> String {
+        let mut asm = String::new();
+        if self.has_sys_init {
+            let code = self.bootstrap();
+            asm.push_str(&code);
+        }
+        asm.push_str(&self.asm);
+        asm.push_str(&self.routines());
+        asm
+    }
+
+    fn bootstrap(&mut self) -> String {
+        let body = std::mem::take(&mut self.asm);
+        self.function = String::from("Sys.bootstrap");
+        self.emit("// bootstrap");
+        self.emit("@256\nD=A\n@SP\nM=D");
+        self.call("Sys.init", 0);
+        std::mem::replace(&mut self.asm, body)
+    }
+
+    fn emit(&mut self, code: &str) {
+        self.asm.push_str(code);
+        self.asm.push('\n');
+    }
+
+    fn translate_command(&mut self, command: &VmCommand) {
+        match command {
+            VmCommand::Add => self.binary("M=D+M"),
+            VmCommand::Sub => self.binary("M=M-D"),
+            VmCommand::And => self.binary("M=D&M"),
+            VmCommand::Or => self.binary("M=D|M"),
+            VmCommand::Neg => self.emit("@SP\nA=M-1\nM=-M"),
+            VmCommand::Not => self.emit("@SP\nA=M-1\nM=!M"),
+            VmCommand::Compare(cmp) => {
+                if !self.comparisons.contains(cmp) {
+                    self.comparisons.push(*cmp);
+                }
+                let ret = self.return_label();
+                self.emit(&format!(
+                    "@{ret}\nD=A\n@R13\nM=D\n@$CMP.{cmp}\n0;JMP\n({ret})"
+                ));
+            }
+            VmCommand::Push(segment, index) => {
+                self.load(*segment, *index);
+                self.emit("@SP\nAM=M+1\nA=A-1\nM=D");
+            }
+            VmCommand::Pop(segment, index) => self.store(*segment, *index),
+            VmCommand::Label(label) => {
+                let label = self.scoped(label);
+                self.emit(&format!("({label})"));
+            }
+            VmCommand::Goto(label) => {
+                let label = self.scoped(label);
+                self.emit(&format!("@{label}\n0;JMP"));
+            }
+            VmCommand::IfGoto(label) => {
+                let label = self.scoped(label);
+                self.emit(&format!("@SP\nAM=M-1\nD=M\n@{label}\nD;JNE"));
+            }
+            VmCommand::Function(name, locals) => {
+                self.function = name.clone();
+                self.has_sys_init |= name == "Sys.init";
+                self.defined.insert(name.clone());
+                self.emit(&format!("({name})"));
+                if *locals > 0 {
+                    self.emit("@SP\nA=M");
+                    for _ in 0..*locals {
+                        self.emit("M=0\nA=A+1");
+                    }
+                    self.emit("D=A\n@SP\nM=D");
+                }
+            }
+            VmCommand::Call(name, args) => self.call(name, *args),
+            VmCommand::Return => {
+                self.uses_return = true;
+                self.emit("@$RETURN\n0;JMP");
+            }
+        }
+    }
+
+    // Pops y into D and leaves A pointing at x
+    fn binary(&mut self, op: &str) {
+        self.emit(&format!("@SP\nAM=M-1\nD=M\nA=A-1\n{op}"));
+    }
+
+    fn call(&mut self, name: &str, args: i16) {
+        self.uses_call = true;
+        self.called.insert(name.to_string());
+        let ret = self.return_label();
+        self.emit(&format!(
+            "@{ret}\nD=A\n@R13\nM=D\n@{args}\nD=A\n@R14\nM=D\n@{name}\nD=A\n@R15\nM=D\n\
+             @$CALL\n0;JMP\n({ret})"
+        ));
+    }
+
+    fn return_label(&mut self) -> String {
+        self.counter += 1;
+        format!("{}$ret.{}", self.function, self.counter)
+    }
+
+    // Labels are only visible inside the function that declares them
+    fn scoped(&self, label: &str) -> String {
+        if self.function.is_empty() {
+            label.to_string()
+        } else {
+            format!("{}${label}", self.function)
+        }
+    }
+
+    // Fixed addresses, for the segments that don't move around
+    fn address(&self, segment: MemSegment, index: i16) -> Option<String> {
+        match segment {
+            MemSegment::Static => Some(format!("{}.{index}", self.class_name)),
+            MemSegment::Temp => Some((5 + index).to_string()),
+            MemSegment::Pointer => Some((3 + index).to_string()),
+            _ => None,
+        }
+    }
+
+    fn base(segment: MemSegment) -> &'static str {
+        match segment {
+            MemSegment::Local => "LCL",
+            MemSegment::Argument => "ARG",
+            MemSegment::This => "THIS",
+            _ => "THAT",
+        }
+    }
+
+    // Leaves the value in D
+    fn load(&mut self, segment: MemSegment, index: i16) {
+        if let Some(address) = self.address(segment, index) {
+            return self.emit(&format!("@{address}\nD=M"));
+        }
+        let code = match (segment, index) {
+            (MemSegment::Constant, 0 | 1) => format!("D={index}"),
+            (MemSegment::Constant, _) => format!("@{index}\nD=A"),
+            (_, 0) => format!("@{}\nA=M\nD=M", Self::base(segment)),
+            (_, 1) => format!("@{}\nA=M+1\nD=M", Self::base(segment)),
+            _ => format!("@{}\nD=M\n@{index}\nA=D+A\nD=M", Self::base(segment)),
+        };
+        self.emit(&code);
+    }
+
+    fn store(&mut self, segment: MemSegment, index: i16) {
+        let pop = "@SP\nAM=M-1\nD=M";
+        if let Some(address) = self.address(segment, index) {
+            return self.emit(&format!("{pop}\n@{address}\nM=D"));
+        }
+        let base = Self::base(segment);
+        // Stepping to the address is shorter than saving it until it gets too far
+        if index <= 6 {
+            let steps = "\nA=A+1".repeat(index as usize);
+            self.emit(&format!("{pop}\n@{base}\nA=M{steps}\nM=D"));
+        } else {
+            self.emit(&format!(
+                "@{base}\nD=M\n@{index}\nD=D+A\n@R13\nM=D\n{pop}\n@R13\nA=M\nM=D"
+            ));
+        }
+    }
+
+    // Only the routines that were jumped to, with a loop in front in case the
+    // program runs off the end of its code without a Sys.init to halt it
+    fn routines(&self) -> String {
+        let mut asm = String::new();
+        if !self.uses_call && !self.uses_return && self.comparisons.is_empty() {
+            return asm;
+        }
+        asm.push_str("// end\n($END)\n@$END\n0;JMP\n");
+        if self.uses_call {
+            // R13 holds the return address, R14 the number of arguments and R15 the function
+            asm.push_str("($CALL)\n@R13\nD=M\n@SP\nAM=M+1\nA=A-1\nM=D\n");
+            for pointer in ["LCL", "ARG", "THIS", "THAT"] {
+                let _ = writeln!(asm, "@{pointer}\nD=M\n@SP\nAM=M+1\nA=A-1\nM=D");
+            }
+            asm.push_str(
+                "@R14\nD=M\n@5\nD=D+A\n@SP\nD=M-D\n@ARG\nM=D\n\
+                 @SP\nD=M\n@LCL\nM=D\n@R15\nA=M\n0;JMP\n",
+            );
+        }
+        if self.uses_return {
+            // R13 walks back through the caller's frame, R14 holds the return address
+            asm.push_str(
+                "($RETURN)\n@LCL\nD=M\n@R13\nM=D\n@5\nA=D-A\nD=M\n@R14\nM=D\n\
+                 @SP\nAM=M-1\nD=M\n@ARG\nA=M\nM=D\n@ARG\nD=M+1\n@SP\nM=D\n",
+            );
+            for pointer in ["THAT", "THIS", "ARG", "LCL"] {
+                let _ = writeln!(asm, "@R13\nAM=M-1\nD=M\n@{pointer}\nM=D");
+            }
+            asm.push_str("@R14\nA=M\n0;JMP\n");
+        }
+        for cmp in &self.comparisons {
+            let jump = match cmp {
+                Comparison::Eq => "JEQ",
+                Comparison::GT => "JGT",
+                Comparison::LT => "JLT",
+            };
+            // x - y can overflow when the signs differ, but then the sign of x decides
+            let _ = writeln!(
+                asm,
+                "($CMP.{cmp})\n@SP\nAM=M-1\nD=M\n@$CMP.{cmp}.YNEG\nD;JLT\n\
+                 @SP\nA=M-1\nD=M\n@$CMP.{cmp}.DIFF\nD;JGE\nD=-1\n@$CMP.{cmp}.TEST\n0;JMP\n\
+                 ($CMP.{cmp}.YNEG)\n@SP\nA=M-1\nD=M\n@$CMP.{cmp}.DIFF\nD;JLT\nD=1\n\
+                 @$CMP.{cmp}.TEST\n0;JMP\n\
+                 ($CMP.{cmp}.DIFF)\n@SP\nA=M\nD=M\nA=A-1\nD=M-D\n\
+                 ($CMP.{cmp}.TEST)\n@$CMP.{cmp}.TRUE\nD;{jump}\nD=0\n@$CMP.{cmp}.END\n0;JMP\n\
+                 ($CMP.{cmp}.TRUE)\nD=-1\n($CMP.{cmp}.END)\n@SP\nA=M-1\nM=D\n@R13\nA=M\n0;JMP"
+            );
+        }
+        asm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm_writer::parse_vm;
+
+    fn translate(source: &str) -> String {
+        let mut translator = VmTranslator::new();
+        translator.translate("Main", &parse_vm(source).unwrap());
+        translator.finish()
+    }
+
+    #[test]
+    fn test_translate() {
+        // Without any functions there's nothing to bootstrap or jump to
+        let asm = translate("push constant 7\npush constant 8\nadd\npop static 2");
+        assert_eq!(
+            asm,
+            "// push constant 7\n@7\nD=A\n@SP\nAM=M+1\nA=A-1\nM=D\n\
+             // push constant 8\n@8\nD=A\n@SP\nAM=M+1\nA=A-1\nM=D\n\
+             // add\n@SP\nAM=M-1\nD=M\nA=A-1\nM=D+M\n\
+             // pop static 2\n@SP\nAM=M-1\nD=M\n@Main.2\nM=D\n"
+        );
+
+        let asm = translate(
+            "function Sys.init 0\ncall Main.main 0\nlabel LOOP\ngoto LOOP\n\
+             function Main.main 1\npush local 0\npush constant 1\nlt\nreturn",
+        );
+        assert!(asm.starts_with("// bootstrap\n@256\nD=A\n@SP\nM=D\n@Sys.bootstrap$ret."));
+        assert!(asm.contains("(Sys.init$LOOP)\n// goto LOOP\n@Sys.init$LOOP\n"));
+        let routines: Vec<_> = asm.lines().filter(|l| l.starts_with("($")).collect();
+        assert_eq!(
+            &routines[..4],
+            ["($END)", "($CALL)", "($RETURN)", "($CMP.lt)"]
+        );
+
+        let mut translator = VmTranslator::new();
+        let source =
+            "function Main.main 0\ncall Main.main 0\ncall Sys.halt 0\ncall Foo.bar 1\nreturn";
+        translator.translate("Main", &parse_vm(source).unwrap());
+        assert_eq!(translator.undefined(), ["Foo.bar", "Sys.halt"]);
+    }
+}
@@ -1,15 +1,13 @@
 use std::{
     fmt::Display,
-    fs::File,
-    io::{BufWriter, Write},
-    path::Path,
+    io::{self, Write},
+    str::FromStr,
 };
 
-use crate::tokens::{Keyword::*, Token};
-
 // Same as VMTranslator enum
 // Someday I want to combine the Compiler/VM Translator/Assembler
-pub enum VmCommand<'a> {
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmCommand {
     // Arithmetic
     Add,
     Sub,
@@ -22,12 +20,12 @@ pub enum VmCommand<'a> {
     Push(MemSegment, i16),
     Pop(MemSegment, i16),
     // Branching
-    Label(&'a str),
-    Goto(&'a str),
-    IfGoto(&'a str),
+    Label(String),
+    Goto(String),
+    IfGoto(String),
     // Function
-    Function(&'a str, i16),
-    Call(&'a str, i16),
+    Function(String, i16),
+    Call(String, i16),
     Return,
 }
 
@@ -43,6 +41,7 @@ pub enum MemSegment {
     Temp,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Comparison {
     Eq,
     GT,
@@ -74,7 +73,91 @@ impl Display for MemSegment {
     }
 }
 
-impl Display for VmCommand<'_> {
+impl MemSegment {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "local" => Some(Self::Local),
+            "argument" => Some(Self::Argument),
+            "this" => Some(Self::This),
+            "that" => Some(Self::That),
+            "constant" => Some(Self::Constant),
+            "static" => Some(Self::Static),
+            "pointer" => Some(Self::Pointer),
+            "temp" => Some(Self::Temp),
+            _ => None,
+        }
+    }
+}
+
+// Reads back a single line of VM code as written by Display, comments already stripped
+impl FromStr for VmCommand {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let number = |word: &str| {
+            word.parse::<i16>()
+                .ok()
+                .filter(|n| *n >= 0)
+                .ok_or_else(|| format!("expected a number between 0 and 32767, found `{word}`"))
+        };
+        let command = match words.as_slice() {
+            ["add"] => VmCommand::Add,
+            ["sub"] => VmCommand::Sub,
+            ["neg"] => VmCommand::Neg,
+            ["eq"] => VmCommand::Compare(Comparison::Eq),
+            ["gt"] => VmCommand::Compare(Comparison::GT),
+            ["lt"] => VmCommand::Compare(Comparison::LT),
+            ["and"] => VmCommand::And,
+            ["or"] => VmCommand::Or,
+            ["not"] => VmCommand::Not,
+            [op @ ("push" | "pop"), segment, index] => {
+                let segment = MemSegment::from_name(segment)
+                    .ok_or_else(|| format!("unknown memory segment `{segment}`"))?;
+                let index = number(index)?;
+                // Both map onto fixed registers, anything past them belongs to something else
+                let limit = match segment {
+                    MemSegment::Pointer => 1,
+                    MemSegment::Temp => 7,
+                    _ => i16::MAX,
+                };
+                if index > limit {
+                    return Err(format!("{segment} only goes up to {limit}"));
+                }
+                if *op == "push" {
+                    VmCommand::Push(segment, index)
+                } else if segment == MemSegment::Constant {
+                    return Err(String::from("can't pop into the constant segment"));
+                } else {
+                    VmCommand::Pop(segment, index)
+                }
+            }
+            ["label", label] => VmCommand::Label(label.to_string()),
+            ["goto", label] => VmCommand::Goto(label.to_string()),
+            ["if-goto", label] => VmCommand::IfGoto(label.to_string()),
+            ["function", name, n] => VmCommand::Function(name.to_string(), number(n)?),
+            ["call", name, n] => VmCommand::Call(name.to_string(), number(n)?),
+            ["return"] => VmCommand::Return,
+            _ => return Err(format!("`{}` is not a VM command", words.join(" "))),
+        };
+        Ok(command)
+    }
+}
+
+// Every command in the source of a .vm file
+// The error holds the line it was found on, counting from 1
+pub fn parse_vm(source: &str) -> Result<Vec<VmCommand>, (usize, String)> {
+    let mut commands = vec![];
+    for (i, line) in source.lines().enumerate() {
+        let line = line.split("//").next().unwrap_or_default().trim();
+        if !line.is_empty() {
+            commands.push(line.parse().map_err(|e| (i + 1, e))?);
+        }
+    }
+    Ok(commands)
+}
+
+impl Display for VmCommand {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             VmCommand::Add => write!(f, "add"),
@@ -96,72 +179,108 @@ impl Display for VmCommand<'_> {
     }
 }
 
-pub trait CodeWriter: Default {
+// Writing is infallible so that callers don't need to check every line,
+// the first error is held onto instead and returned from flush
+pub trait CodeWriter {
     fn write(&mut self, contents: impl Display);
-    fn flush(&mut self);
-    fn new(filename: &str) -> Self;
+    fn flush(&mut self) -> io::Result<()>;
 }
 
-#[derive(Default)]
-pub struct VmWriter {
-    writer: Option<BufWriter<File>>,
-    if_counter: u16,
-    while_counter: u16,
+// Writes one VM command per line to any sink, e.g. a file, stdout or a Vec<u8>
+pub struct VmWriter<W: Write> {
+    writer: W,
+    error: Option<io::Error>,
 }
 
-impl CodeWriter for VmWriter {
-    fn new(filename: &str) -> Self {
-        let file =
-            File::create(Path::new(filename).with_extension("vm")).expect("could not create file");
-        let writer = BufWriter::new(file);
+impl<W: Write> VmWriter<W> {
+    pub fn new(writer: W) -> Self {
         VmWriter {
-            writer: Some(writer),
-            if_counter: 0,
-            while_counter: 0,
+            writer,
+            error: None,
         }
     }
 
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> CodeWriter for VmWriter<W> {
     fn write(&mut self, contents: impl Display) {
-        writeln!(self.writer.as_mut().expect("no writer"), "{contents}").expect("failed to write");
+        if self.error.is_none() {
+            self.error = writeln!(self.writer, "{contents}").err();
+        }
     }
 
-    fn flush(&mut self) {
-        self.writer.as_mut().expect("no writer").flush().unwrap();
+    fn flush(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.writer.flush(),
+        }
     }
 }
 
-impl VmWriter {
-    pub fn generate_label(&mut self, label: &str) -> String {
-        let counter = if label == "if" {
-            &mut self.if_counter
-        } else {
-            &mut self.while_counter
-        };
-        let label = format!("{label}{counter}");
-        *counter += 1;
-        label
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    pub fn write_constant(&mut self, t: Token) {
-        match t {
-            Token::Keyword(True) => {
-                self.write(VmCommand::Push(MemSegment::Constant, 1));
-                self.write(VmCommand::Neg);
-            }
-            Token::Keyword(False) | Token::Keyword(Null) => {
-                self.write(VmCommand::Push(MemSegment::Constant, 0))
-            }
-            Token::Keyword(This) => self.write(VmCommand::Push(MemSegment::Pointer, 0)),
-            Token::IntConstant(i) => self.write(VmCommand::Push(MemSegment::Constant, i)),
-            Token::StringConstant(s) => {
-                self.write(VmCommand::Push(MemSegment::Constant, s.len() as i16));
-                self.write(VmCommand::Call("String.new", 1));
-                for c in s.chars() {
-                    self.write(VmCommand::Push(MemSegment::Constant, c as i16));
-                    self.write(VmCommand::Call("String.appendChar", 2));
-                }
-            }
-            _ => { /*only passing constants*/ }
+    // Fails every write, like a full disk
+    struct Full;
+
+    impl Write for Full {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("no space left"))
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
         }
     }
+
+    #[test]
+    fn test_write_to_any_sink() {
+        let mut writer = VmWriter::new(vec![]);
+        writer.write(VmCommand::Push(MemSegment::Constant, 7));
+        writer.write(VmCommand::Call(String::from("Output.printInt"), 1));
+        assert!(writer.flush().is_ok());
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "push constant 7\ncall Output.printInt 1\n"
+        );
+
+        let mut writer = VmWriter::new(Full);
+        writer.write(VmCommand::Return);
+        writer.write(VmCommand::Return);
+        assert_eq!(writer.flush().unwrap_err().to_string(), "no space left");
+    }
+
+    #[test]
+    fn test_parse_vm() {
+        let source = "// Adds two numbers\nfunction Main.add 0\n  push argument 0\n\
+                      push argument 1 // second\nadd\n\nreturn\n";
+        let commands = parse_vm(source).unwrap();
+        assert_eq!(commands.len(), 5);
+        let written: Vec<_> = commands.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            parse_vm(&written.join("\n")).unwrap(),
+            commands,
+            "what is written can be read back"
+        );
+
+        assert_eq!(
+            parse_vm("push constant 1\npop constant 0").unwrap_err(),
+            (2, String::from("can't pop into the constant segment"))
+        );
+        assert_eq!(
+            parse_vm("push temp 8").unwrap_err().1,
+            "temp only goes up to 7"
+        );
+        assert_eq!(
+            parse_vm("push local -1").unwrap_err().1,
+            "expected a number between 0 and 32767, found `-1`"
+        );
+        assert_eq!(
+            parse_vm("jump x").unwrap_err().1,
+            "`jump x` is not a VM command"
+        );
+    }
 }
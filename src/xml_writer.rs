@@ -1,34 +1,63 @@
 use std::{
     fmt::Display,
-    fs::File,
-    io::{BufWriter, Write},
-    path::Path,
+    io::{self, Write},
 };
 
-use crate::vm_writer::CodeWriter;
+use crate::{tokenizer::Tokenizer, vm_writer::CodeWriter};
 
-#[derive(Default)]
-pub struct XMLWriter {
-    writer: Option<BufWriter<File>>,
+pub struct XMLWriter<W: Write> {
+    writer: W,
+    error: Option<io::Error>,
+    // Nesting depth of the element currently open, two spaces per level
+    indent: usize,
 }
-impl CodeWriter for XMLWriter {
-    fn new(filename: &str) -> Self {
-        let file =
-            File::create(Path::new(filename).with_extension("xml")).expect("could not create file");
-        let writer = BufWriter::new(file);
+
+impl<W: Write> CodeWriter for XMLWriter<W> {
+    fn write(&mut self, contents: impl Display) {
+        let indent = "  ".repeat(self.indent);
+        if self.error.is_none() {
+            self.error = writeln!(self.writer, "{indent}{contents}").err();
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.writer.flush(),
+        }
+    }
+}
+
+impl<W: Write> XMLWriter<W> {
+    pub fn new(writer: W) -> Self {
         XMLWriter {
-            writer: Some(writer),
+            writer,
+            error: None,
+            indent: 0,
         }
     }
 
-    fn write(&mut self, contents: impl Display) {
-        writeln!(self.writer.as_mut().unwrap(), "{contents}").expect("failed to write");
-        self.flush();
+    pub fn into_inner(self) -> W {
+        self.writer
     }
 
-    fn flush(&mut self) {
-        self.writer.as_mut().unwrap().flush().unwrap();
+    // Every token in the file as a flat list, matching the course's XxxT.xml files
+    pub fn write_tokens(&mut self, mut tokenizer: Tokenizer) {
+        self.write("<tokens>");
+        while let Some((token, _)) = tokenizer.advance() {
+            self.write(token);
+        }
+        self.write("</tokens>");
     }
-}
 
-impl XMLWriter {}
+    // Empty elements are still written as an opening and closing tag on separate lines
+    pub fn open(&mut self, tag: &str) {
+        self.write(format!("<{tag}>"));
+        self.indent += 1;
+    }
+
+    pub fn close(&mut self, tag: &str) {
+        self.indent = self.indent.saturating_sub(1);
+        self.write(format!("</{tag}>"));
+    }
+}
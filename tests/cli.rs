@@ -0,0 +1,53 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+// A fresh directory with the given classes in it, unique to the test and the run
+fn project(name: &str, classes: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("{name}_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    for (file, source) in classes {
+        fs::write(dir.join(file), source).unwrap();
+    }
+    dir
+}
+
+fn compile(args: &[&str], file: &Path) -> i32 {
+    Command::new(env!("CARGO_BIN_EXE_hack_jack_compiler"))
+        .args(args)
+        .arg(file)
+        .status()
+        .unwrap()
+        .code()
+        .unwrap()
+}
+
+#[test]
+fn test_single_file_links_its_classmates() {
+    let dir = project(
+        "hack_jack_test_single_file_links_its_classmates",
+        &[
+            (
+                "Main.jack",
+                "class Main { function void main() { do Foo.twice(2); return; } }",
+            ),
+            (
+                "Foo.jack",
+                "class Foo { function int twice(int x) { return x + x; } }",
+            ),
+            (
+                "Sys.vm",
+                "function Sys.init 0\ncall Main.main 0\npop temp 0\nlabel HALT\ngoto HALT",
+            ),
+        ],
+    );
+    assert_eq!(compile(&["--emit", "asm,hack"], &dir.join("Main.jack")), 0);
+    let asm = fs::read_to_string(dir.join("Main.asm")).unwrap();
+    assert!(asm.contains("@Foo.twice\n"));
+    assert!(asm.contains("(Foo.twice)\n"));
+    assert!(asm.contains("(Sys.init)\n"));
+    assert!(dir.join("Main.hack").exists());
+}